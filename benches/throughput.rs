@@ -0,0 +1,144 @@
+//! Requests per second through the proxy for cached responses, at several client
+//! concurrencies and with the memory cache unsharded and sharded.
+//!
+//! Run with `cargo bench --bench throughput`.
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    client::HttpConnector,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Client, Response, Server,
+};
+
+use proxy_with_cache::{
+    cache_key::DefaultKeyBuilder,
+    config::{CacheConfig, Config, LogLevel},
+    controller::Controller,
+    forwarding::Peer,
+    metrics::Metrics,
+    router::Route,
+    store::MemoryStore,
+};
+
+
+const PATHS: usize = 1000;
+const DURATION: Duration = Duration::new(3, 0);
+const CONCURRENCY: [usize; 3] = [1, 8, 64];
+const SHARDS: [usize; 2] = [1, 16];
+
+
+#[tokio::main]
+async fn main() {
+    let origin = start_origin();
+    for shards in SHARDS {
+        let proxy = start_proxy(origin, shards);
+        let client = Client::new();
+        // Fill the cache so that the measurement only sees hits.
+        for path in 0..PATHS {
+            get(&client, proxy, path).await;
+        }
+        for clients in CONCURRENCY {
+            let rate = measure(&client, proxy, clients).await;
+            println!("shards={:<3} clients={:<3} {:>10.0} req/s", shards, clients, rate);
+        }
+    }
+}
+
+fn start_origin() -> SocketAddr {
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_| async {
+            Ok::<_, Infallible>(
+                Response::builder()
+                    .header("cache-control", "max-age=600")
+                    .body(Body::from(vec![b'x'; 1024]))
+                    .unwrap(),
+            )
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+fn start_proxy(origin: SocketAddr, shards: usize) -> SocketAddr {
+    let config = Config {
+        log_level: LogLevel::Error,
+        routes: vec![Route {
+            name: "origin".to_owned(),
+            host: None,
+            prefix: None,
+            upstream: format!("http://{}", origin),
+            strip_prefix: false,
+            key: None,
+        }],
+        cache: CacheConfig { shards, ..CacheConfig::default() },
+        ..Config::default()
+    };
+    let store = Arc::new(MemoryStore::new(
+        shards,
+        config.cache.max_entries,
+        config.cache.max_bytes,
+        config.cache.eviction_policy,
+    ));
+    let controller = Arc::new(Controller::new(
+        &config,
+        store,
+        Box::new(DefaultKeyBuilder::default()),
+        Arc::new(Metrics::default()),
+    ));
+
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let controller = controller.clone();
+        let peer = Peer { addr: conn.remote_addr(), tls: false };
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| controller.clone().process(req, peer)))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+async fn measure(client: &Client<HttpConnector>, proxy: SocketAddr, clients: usize) -> f64 {
+    let done = Arc::new(AtomicBool::new(false));
+    let requests = Arc::new(AtomicU64::new(0));
+    let workers: Vec<_> = (0..clients)
+        .map(|worker| {
+            let (client, done, requests) = (client.clone(), done.clone(), requests.clone());
+            tokio::spawn(async move {
+                let mut path = worker * PATHS / clients;
+                while !done.load(Ordering::Relaxed) {
+                    get(&client, proxy, path).await;
+                    requests.fetch_add(1, Ordering::Relaxed);
+                    path = (path + 1) % PATHS;
+                }
+            })
+        })
+        .collect();
+    let start = Instant::now();
+    tokio::time::sleep(DURATION).await;
+    done.store(true, Ordering::Relaxed);
+    for worker in workers {
+        worker.await.unwrap();
+    }
+    requests.load(Ordering::Relaxed) as f64 / start.elapsed().as_secs_f64()
+}
+
+async fn get(client: &Client<HttpConnector>, proxy: SocketAddr, path: usize) {
+    let uri = format!("http://{}/item/{}", proxy, path).parse().unwrap();
+    let response = client.get(uri).await.unwrap();
+    assert!(response.status().is_success(), "unexpected status {}", response.status());
+    hyper::body::to_bytes(response.into_body()).await.unwrap();
+}
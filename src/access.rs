@@ -0,0 +1,417 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use hyper::{
+    client::connect::dns::{GaiResolver, Name},
+    service::Service,
+    Uri,
+};
+use serde::{Deserialize, Deserializer};
+
+
+/// An allowed upstream origin: `[scheme://]host[:port]` where the scheme and port may be `*`
+/// and the host may be `*` or start with `*.` to match any subdomain. Without a scheme both
+/// http and https match, without a port only the scheme's default port does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginPattern {
+    scheme: Option<String>,
+    host: HostPattern,
+    port: PortPattern,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    Any,
+    Exact(String),
+    Subdomain(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PortPattern {
+    Any,
+    Default,
+    Exact(u16),
+}
+
+impl OriginPattern {
+    pub fn matches(&self, uri: &Uri) -> bool {
+        let scheme = match uri.scheme_str() {
+            Some(scheme) => scheme.to_ascii_lowercase(),
+            None => return false,
+        };
+        let host = match uri.host() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase(),
+            None => return false,
+        };
+        let scheme_matches = match &self.scheme {
+            Some(pattern) => *pattern == scheme,
+            None => scheme == "http" || scheme == "https",
+        };
+        let host_matches = match &self.host {
+            HostPattern::Any => true,
+            HostPattern::Exact(pattern) => *pattern == host,
+            HostPattern::Subdomain(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+        };
+        let port_matches = match self.port {
+            PortPattern::Any => true,
+            PortPattern::Default => uri.port_u16().is_none_or(|port| Some(port) == default_port(&scheme)),
+            PortPattern::Exact(pattern) => uri.port_u16().or_else(|| default_port(&scheme)) == Some(pattern),
+        };
+        scheme_matches && host_matches && port_matches
+    }
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+impl FromStr for OriginPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OriginPattern, String> {
+        let invalid = || format!("`{}` is not a [scheme://]host[:port] origin pattern", s);
+        let (scheme, rest) = match s.split_once("://") {
+            Some(("*", rest)) => (None, rest),
+            Some((scheme, rest)) if scheme == "http" || scheme == "https" => (Some(scheme.to_owned()), rest),
+            Some(_) => return Err(invalid()),
+            None => (None, s),
+        };
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) if !host.ends_with(':') && !port.contains(']') => (host, Some(port)),
+            _ => (rest, None),
+        };
+        let port = match port {
+            None => PortPattern::Default,
+            Some("*") => PortPattern::Any,
+            Some(port) => PortPattern::Exact(port.parse().map_err(|_| invalid())?),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid());
+        }
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Some(domain) = host.strip_prefix("*.") {
+            HostPattern::Subdomain(format!(".{}", domain))
+        } else {
+            HostPattern::Exact(host)
+        };
+        Ok(OriginPattern { scheme, host, port })
+    }
+}
+
+impl<'de> Deserialize<'de> for OriginPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<OriginPattern, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// An IP network in CIDR notation such as `10.0.0.0/8` or `fe80::/10`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            IpAddr::V4(_) => address,
+        };
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(u32::from(network) as u128, u32::from(address) as u128, self.prefix, 32)
+            },
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(u128::from(network), u128::from(address), self.prefix, 128)
+            },
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, address: u128, prefix: u8, bits: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    network >> shift == address >> shift
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        let invalid = || format!("`{}` is not a network in CIDR notation", s);
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(Network { address, prefix })
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Network, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Loopback, private, link-local, shared, benchmarking, multicast, reserved and unspecified
+/// ranges, plus the IPv6 ranges that embed an IPv4 address: IPv4-compatible, NAT64 and 6to4.
+pub fn private_networks() -> Vec<Network> {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        "::/96",
+        "64:ff9b::/96",
+        "64:ff9b:1::/48",
+        "2002::/16",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|network| network.parse().unwrap())
+    .collect()
+}
+
+#[derive(Debug)]
+pub struct DeniedAddress(pub IpAddr);
+
+impl fmt::Display for DeniedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "address {} is in a denied network", self.0)
+    }
+}
+
+impl std::error::Error for DeniedAddress {}
+
+pub fn is_denied(networks: &[Network], address: IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(address))
+}
+
+/// Why `FilteringResolver` found no address to connect to.
+#[derive(Debug)]
+pub enum ResolveError {
+    Lookup(io::Error),
+    Denied(DeniedAddress),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolveError::Lookup(error) => write!(f, "lookup failed: {}", error),
+            ResolveError::Denied(denied) => write!(f, "{}", denied),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ResolveError::Lookup(error) => Some(error),
+            ResolveError::Denied(denied) => Some(denied),
+        }
+    }
+}
+
+/// A DNS resolver that drops addresses in denied networks, so the check happens on the
+/// addresses actually connected to rather than on a lookup that could change in between.
+#[derive(Clone)]
+pub struct FilteringResolver {
+    inner: GaiResolver,
+    denied: Arc<Vec<Network>>,
+}
+
+impl FilteringResolver {
+    pub fn new(denied: Vec<Network>) -> FilteringResolver {
+        FilteringResolver {
+            inner: GaiResolver::new(),
+            denied: Arc::new(denied),
+        }
+    }
+}
+
+impl Service<Name> for FilteringResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = ResolveError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, ResolveError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ResolveError>> {
+        self.inner.poll_ready(cx).map_err(ResolveError::Lookup)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolving = self.inner.call(name);
+        let denied = self.denied.clone();
+        Box::pin(async move {
+            let mut rejected = None;
+            let addresses: Vec<SocketAddr> = resolving
+                .await
+                .map_err(ResolveError::Lookup)?
+                .filter(|address| {
+                    let is_denied = is_denied(&denied, address.ip());
+                    if is_denied {
+                        rejected = Some(address.ip());
+                    }
+                    !is_denied
+                })
+                .collect();
+            match rejected {
+                Some(address) if addresses.is_empty() => Err(ResolveError::Denied(DeniedAddress(address))),
+                _ => Ok(addresses.into_iter()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> OriginPattern {
+        s.parse().unwrap()
+    }
+
+    fn uri(s: &str) -> Uri {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_origin_patterns() {
+        assert_eq!(
+            pattern("https://Example.com:8443"),
+            OriginPattern {
+                scheme: Some("https".to_owned()),
+                host: HostPattern::Exact("example.com".to_owned()),
+                port: PortPattern::Exact(8443),
+            }
+        );
+        assert_eq!(
+            pattern("*://*.example.com:*"),
+            OriginPattern {
+                scheme: None,
+                host: HostPattern::Subdomain(".example.com".to_owned()),
+                port: PortPattern::Any,
+            }
+        );
+        assert_eq!(
+            pattern("[::1]"),
+            OriginPattern { scheme: None, host: HostPattern::Exact("::1".to_owned()), port: PortPattern::Default }
+        );
+        for invalid in ["ftp://example.com", "example.com:http", "", "https://", "example.com/path"] {
+            assert!(invalid.parse::<OriginPattern>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn matches_scheme_host_and_port() {
+        let exact = pattern("https://example.com");
+        assert!(exact.matches(&uri("https://example.com/path")));
+        assert!(exact.matches(&uri("https://EXAMPLE.com:443")));
+        assert!(!exact.matches(&uri("http://example.com")));
+        assert!(!exact.matches(&uri("https://example.com:8443")));
+        assert!(!exact.matches(&uri("https://example.com.evil.net")));
+
+        let any_scheme = pattern("example.com:8080");
+        assert!(any_scheme.matches(&uri("http://example.com:8080")));
+        assert!(any_scheme.matches(&uri("https://example.com:8080")));
+        assert!(!any_scheme.matches(&uri("https://example.com")));
+
+        assert!(pattern("http://127.0.0.1:*").matches(&uri("http://127.0.0.1:1234")));
+        assert!(pattern("[::1]:*").matches(&uri("http://[::1]:8080")));
+    }
+
+    #[test]
+    fn subdomain_patterns_exclude_the_domain_itself() {
+        let subdomains = pattern("*.example.com");
+        assert!(subdomains.matches(&uri("https://api.example.com")));
+        assert!(subdomains.matches(&uri("https://a.b.example.com")));
+        assert!(!subdomains.matches(&uri("https://example.com")));
+        assert!(!subdomains.matches(&uri("https://badexample.com")));
+    }
+
+    #[test]
+    fn parses_networks() {
+        assert_eq!("10.0.0.0/8".parse(), Ok(Network { address: ip("10.0.0.0"), prefix: 8 }));
+        assert_eq!("::1".parse(), Ok(Network { address: ip("::1"), prefix: 128 }));
+        assert_eq!("192.168.1.1".parse(), Ok(Network { address: ip("192.168.1.1"), prefix: 32 }));
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0/8", "example.com/8", "10.0.0.0/x"] {
+            assert!(invalid.parse::<Network>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn network_membership() {
+        let network: Network = "172.16.0.0/12".parse().unwrap();
+        assert!(network.contains(ip("172.16.0.1")));
+        assert!(network.contains(ip("172.31.255.255")));
+        assert!(!network.contains(ip("172.32.0.0")));
+        assert!(!network.contains(ip("::1")));
+        // IPv4-mapped addresses are checked as IPv4.
+        assert!(network.contains(ip("::ffff:172.16.0.1")));
+        assert!("0.0.0.0/0".parse::<Network>().unwrap().contains(ip("8.8.8.8")));
+        assert!("fe80::/10".parse::<Network>().unwrap().contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn private_networks_deny_internal_addresses() {
+        let denied = private_networks();
+        for address in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "169.254.169.254",
+            "198.18.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::127.0.0.1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::7f00:1",
+            "2002:7f00:1::",
+            "fd00::1",
+            "ff02::1",
+        ] {
+            assert!(is_denied(&denied, ip(address)), "{}", address);
+        }
+        for address in ["8.8.8.8", "1.1.1.1", "2606:4700::1111", "::ffff:8.8.8.8"] {
+            assert!(!is_denied(&denied, ip(address)), "{}", address);
+        }
+    }
+}
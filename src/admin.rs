@@ -0,0 +1,365 @@
+use std::{convert::Infallible, sync::Arc, time::SystemTime};
+
+use hyper::{header, Body, Method, Request, Response, StatusCode, Uri};
+use serde_json::{json, Value};
+
+use crate::{
+    cache::EntryInfo,
+    cache_key::{self, CacheKey},
+    metrics::Metrics,
+    store::CacheStore,
+};
+
+
+const SELECTORS: &str = "one of url, prefix, glob, host or all=true";
+
+
+/// The admin API, served on its own listener and protected by a bearer token:
+///
+/// - `GET /entries` lists stored keys,
+/// - `GET /entry?url=<url>` shows every variant stored for one URL,
+/// - `DELETE /entries` purges keys, `all=true` flushes the whole cache,
+/// - `GET /metrics` returns the metrics in the Prometheus text format.
+///
+/// `/entries` takes at most one selector: `url` (exact), `prefix`, `glob` (with `*` and `?`)
+/// or `host`.
+pub struct Admin {
+    store: Arc<dyn CacheStore>,
+    metrics: Arc<Metrics>,
+    token: String,
+}
+
+/// A failed admin request, answered with RFC 9457 problem details like the proxy's own errors.
+#[derive(Debug)]
+struct Problem {
+    status: StatusCode,
+    detail: String,
+}
+
+#[derive(Debug)]
+enum Selector {
+    All,
+    Url(String),
+    Prefix(String),
+    Glob(String),
+    Host(String),
+}
+
+impl Admin {
+    pub fn new(store: Arc<dyn CacheStore>, metrics: Arc<Metrics>, token: String) -> Admin {
+        Admin { store, metrics, token }
+    }
+
+    pub async fn process(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        if !self.authorized(&req) {
+            let mut response = Problem::new(StatusCode::UNAUTHORIZED, "a valid bearer token is required").into_response();
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            return Ok(response);
+        }
+        let uri = req.uri();
+        let response = match (req.method(), uri.path()) {
+            (&Method::GET, "/entries") => self.list(uri).await,
+            (&Method::DELETE, "/entries") => self.purge(uri).await,
+            (&Method::GET, "/entry") => self.entry(uri).await,
+            (&Method::GET, "/metrics") => Ok(self.metrics().await),
+            (_, "/entries" | "/entry" | "/metrics") => Err(Problem::new(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")),
+            _ => Err(Problem::new(StatusCode::NOT_FOUND, "no such endpoint")),
+        };
+        Ok(response.unwrap_or_else(Problem::into_response))
+    }
+
+    fn authorized(&self, req: &Request<Body>) -> bool {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| constant_time_eq(token.trim().as_bytes(), self.token.as_bytes()))
+    }
+
+    async fn list(&self, uri: &Uri) -> Result<Response<Body>, Problem> {
+        let selector = Selector::from_uri(uri)?.unwrap_or(Selector::All);
+        let entries: Vec<Value> = self.entries(&selector).await?
+            .iter()
+            .map(|entry| {
+                json!({
+                    "method": entry.key.method.as_str(),
+                    "url": entry.key.url(),
+                    "variants": entry.variants.len(),
+                    "size": entry.variants.iter().map(|variant| variant.size).sum::<usize>(),
+                    "age": entry.variants.iter().map(|variant| variant.age.as_secs()).min(),
+                    "ttl": entry.variants.iter().map(|variant| variant.ttl.as_secs()).max(),
+                    "hits": entry.hits,
+                })
+            })
+            .collect();
+        Ok(ok(json!({ "entries": entries })))
+    }
+
+    async fn entry(&self, uri: &Uri) -> Result<Response<Body>, Problem> {
+        let selector = match Selector::from_uri(uri)? {
+            Some(Selector::Url(url)) => Selector::Url(url),
+            _ => return Err(Problem::new(StatusCode::BAD_REQUEST, "the url parameter is required")),
+        };
+        let entries = self.entries(&selector).await?;
+        if entries.is_empty() {
+            return Err(Problem::new(StatusCode::NOT_FOUND, "no entry is stored for this URL"));
+        }
+        let entries: Vec<Value> = entries
+            .iter()
+            .map(|entry| {
+                json!({
+                    "method": entry.key.method.as_str(),
+                    "url": entry.key.url(),
+                    "key_headers": entry.key.headers
+                        .iter()
+                        .map(|(name, value)| json!([name.as_str(), value]))
+                        .collect::<Vec<_>>(),
+                    "hits": entry.hits,
+                    "variants": entry.variants
+                        .iter()
+                        .map(|variant| {
+                            json!({
+                                "status": variant.status.as_u16(),
+                                "headers": variant.headers
+                                    .iter()
+                                    .map(|(name, value)| json!([name.as_str(), String::from_utf8_lossy(value.as_bytes())]))
+                                    .collect::<Vec<_>>(),
+                                "size": variant.size,
+                                "age": variant.age.as_secs(),
+                                "ttl": variant.ttl.as_secs(),
+                            })
+                        })
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        Ok(ok(json!({ "entries": entries })))
+    }
+
+    async fn purge(&self, uri: &Uri) -> Result<Response<Body>, Problem> {
+        let selector = match Selector::from_uri(uri)? {
+            Some(selector) => selector,
+            None => return Err(Problem::new(StatusCode::BAD_REQUEST, format!("expected {}", SELECTORS))),
+        };
+        let keys = self.store.keys().await.map_err(store_error)?;
+        let mut purged = 0;
+        for key in keys.iter().filter(|key| selector.matches(key)) {
+            self.store.delete(key).await.map_err(store_error)?;
+            purged += 1;
+        }
+        Ok(ok(json!({ "purged": purged })))
+    }
+
+    async fn metrics(&self) -> Response<Body> {
+        let body = self.metrics.render(&self.store.stats().await);
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn entries(&self, selector: &Selector) -> Result<Vec<EntryInfo>, Problem> {
+        let mut entries = self.store.entries(SystemTime::now()).await.map_err(store_error)?;
+        entries.retain(|entry| selector.matches(&entry.key));
+        entries.sort_by_cached_key(|entry| (entry.key.url(), entry.key.method.to_string()));
+        Ok(entries)
+    }
+}
+
+impl Problem {
+    fn new(status: StatusCode, detail: impl Into<String>) -> Problem {
+        Problem { status, detail: detail.into() }
+    }
+
+    fn into_response(self) -> Response<Body> {
+        let problem = json!({
+            "type": "about:blank",
+            "title": self.status.canonical_reason().unwrap_or_default(),
+            "status": self.status.as_u16(),
+            "detail": self.detail,
+        });
+        Response::builder()
+            .status(self.status)
+            .header(header::CONTENT_TYPE, "application/problem+json")
+            .body(Body::from(problem.to_string()))
+            .unwrap()
+    }
+}
+
+impl Selector {
+    /// The selector in the query string, `None` if it has none.
+    fn from_uri(uri: &Uri) -> Result<Option<Selector>, Problem> {
+        let mut selector = None;
+        for (name, value) in form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()) {
+            let parsed = match &*name {
+                "all" if value == "true" => Selector::All,
+                "url" => Selector::Url(normalize_url(&value)),
+                "prefix" => Selector::Prefix(normalize_url(&value)),
+                "glob" => Selector::Glob(value.into_owned()),
+                "host" => Selector::Host(value.to_ascii_lowercase()),
+                _ => return Err(Problem::new(StatusCode::BAD_REQUEST, format!("unexpected parameter `{}`", name))),
+            };
+            if selector.replace(parsed).is_some() {
+                return Err(Problem::new(StatusCode::BAD_REQUEST, format!("expected {}", SELECTORS)));
+            }
+        }
+        Ok(selector)
+    }
+
+    fn matches(&self, key: &CacheKey) -> bool {
+        match self {
+            Selector::All => true,
+            Selector::Url(url) => key.url() == *url,
+            Selector::Prefix(prefix) => key.url().starts_with(prefix.as_str()),
+            Selector::Glob(pattern) => glob_matches(pattern.as_bytes(), key.url().as_bytes()),
+            Selector::Host(host) => key.origin.parse::<Uri>().is_ok_and(|origin| {
+                origin.host() == Some(host.as_str()) || origin.authority().is_some_and(|authority| authority == host.as_str())
+            }),
+        }
+    }
+}
+
+/// Puts the origin and path in the form cache keys use, leaving the query as given.
+fn normalize_url(url: &str) -> String {
+    let uri = match url.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return url.to_owned(),
+    };
+    match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme), Some(authority)) => {
+            let origin = cache_key::normalize_origin(&format!("{}://{}", scheme, authority));
+            let path = cache_key::normalize_path(uri.path());
+            match uri.query() {
+                Some(query) => format!("{}{}?{}", origin, path, query),
+                None => format!("{}{}", origin, path),
+            }
+        },
+        _ => url.to_owned(),
+    }
+}
+
+// `*` matches any run of bytes and `?` any single byte.
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    t = matched + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+fn ok(body: Value) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn store_error(error: std::io::Error) -> Problem {
+    Problem::new(StatusCode::INTERNAL_SERVER_ERROR, format!("cache store failed: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache_key::{DefaultKeyBuilder, KeyBuilder},
+        eviction::PolicyKind,
+        store::MemoryStore,
+    };
+
+    fn key(url: &str) -> CacheKey {
+        let uri: Uri = url.parse().unwrap();
+        let origin = format!("{}://{}", uri.scheme_str().unwrap(), uri.authority().unwrap());
+        let (parts, _) = Request::get(uri.path_and_query().unwrap().as_str()).body(()).unwrap().into_parts();
+        DefaultKeyBuilder::default().build(&parts, &origin)
+    }
+
+    fn selector(query: &str) -> Selector {
+        Selector::from_uri(&format!("/entries?{}", query).parse().unwrap()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn globs_match_runs_and_single_bytes() {
+        let matches = |pattern: &str, text: &str| glob_matches(pattern.as_bytes(), text.as_bytes());
+        assert!(matches("*", ""));
+        assert!(matches("https://*.example.com/*", "https://api.example.com/v1/blocks"));
+        assert!(matches("*/blocks?", "https://a/blocks/"));
+        assert!(matches("a*b*c", "abbbc"));
+        assert!(matches("a*b*c", "axxbyyc"));
+        assert!(matches("a**", "a"));
+        assert!(!matches("a*b*c", "axxbyy"));
+        assert!(!matches("*/blocks?", "https://a/blocks"));
+        assert!(!matches("abc", "abcd"));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn selectors_match_keys_in_their_normalized_form() {
+        let stored = key("http://example.com/btc/blocks?limit=1");
+        assert!(selector("url=HTTP%3A%2F%2FExample.com%3A80%2Fbtc%2Fx%2F..%2F%2562locks%3Flimit%3D1").matches(&stored));
+        assert!(!selector("url=http://example.com/btc/blocks").matches(&stored));
+        assert!(selector("prefix=http://EXAMPLE.com/btc/./").matches(&stored));
+        assert!(!selector("prefix=http://example.com/eth").matches(&stored));
+        assert!(selector("glob=*/btc/*").matches(&stored));
+        assert!(selector("host=Example.com").matches(&stored));
+        assert!(!selector("host=example.org").matches(&stored));
+        assert!(selector("all=true").matches(&stored));
+
+        let with_port = key("http://example.com:8080/");
+        assert!(selector("host=example.com").matches(&with_port));
+        assert!(selector("host=example.com:8080").matches(&with_port));
+        assert!(!selector("host=example.com:9090").matches(&with_port));
+    }
+
+    #[test]
+    fn takes_at_most_one_known_selector() {
+        let parse = |query: &str| Selector::from_uri(&format!("/entries?{}", query).parse().unwrap());
+        assert!(matches!(parse(""), Ok(None)));
+        assert!(matches!(parse("all=false"), Err(Problem { status: StatusCode::BAD_REQUEST, .. })));
+        assert!(matches!(parse("url=a&host=b"), Err(Problem { status: StatusCode::BAD_REQUEST, .. })));
+        assert!(matches!(parse("path=/"), Err(Problem { status: StatusCode::BAD_REQUEST, .. })));
+    }
+
+    #[tokio::test]
+    async fn requires_the_bearer_token() {
+        let store = Arc::new(MemoryStore::new(1, 10, 1024, PolicyKind::Lru));
+        let admin = Arc::new(Admin::new(store, Arc::new(Metrics::default()), "secret".to_owned()));
+        let status = |authorization: Option<&str>| {
+            let admin = admin.clone();
+            let mut request = Request::get("/entries");
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            async move { admin.process(request.body(Body::empty()).unwrap()).await.unwrap().status() }
+        };
+        assert_eq!(status(Some("Bearer secret")).await, StatusCode::OK);
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer secre")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer secrets")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Basic secret")).await, StatusCode::UNAUTHORIZED);
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"Secret"));
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{http::HeaderValue, HeaderMap, StatusCode};
+
+use crate::{
+    cache_key::{CacheKey, UrlIndex},
+    cached_response::CachedResponse,
+    eviction::EvictionPolicy,
+};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub evictions: u64,
+    pub rejections: u64,
+}
+
+/// A stored key as listed by the admin API.
+#[derive(Debug, Clone)]
+pub struct EntryInfo {
+    pub key: CacheKey,
+    pub hits: u64,
+    pub variants: Vec<VariantInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VariantInfo {
+    pub status: StatusCode,
+    pub headers: HeaderMap<HeaderValue>,
+    pub size: usize,
+    pub age: Duration,
+    pub ttl: Duration,
+}
+
+impl VariantInfo {
+    pub fn new(cached: &CachedResponse, size: usize, now: SystemTime) -> VariantInfo {
+        VariantInfo {
+            status: cached.status(),
+            headers: cached.headers().clone(),
+            size,
+            age: cached.age(now),
+            ttl: cached.time_to_live(now),
+        }
+    }
+}
+
+/// The response store, bounded by number of keys and total body bytes.
+pub struct Cache {
+    entries: HashMap<CacheKey, Entry>,
+    expiries: Expiries,
+    urls: UrlIndex,
+    policy: Box<dyn EvictionPolicy>,
+    max_entries: usize,
+    max_bytes: usize,
+    bytes: usize,
+    evictions: u64,
+    rejections: u64,
+}
+
+struct Entry {
+    variants: Vec<CachedResponse>,
+    expiry: Expiry,
+    hits: u64,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize, max_bytes: usize, policy: Box<dyn EvictionPolicy>) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            expiries: Expiries::default(),
+            urls: UrlIndex::default(),
+            policy,
+            max_entries,
+            max_bytes,
+            bytes: 0,
+            evictions: 0,
+            rejections: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &CacheKey, request_headers: &HeaderMap<HeaderValue>) -> Option<CachedResponse> {
+        self.policy.touch(key);
+        let entry = self.entries.get_mut(key)?;
+        let cached = entry.variants.iter().find(|cached| cached.matches_vary(request_headers))?.clone();
+        entry.hits += 1;
+        Some(cached)
+    }
+
+    pub fn insert(&mut self, key: CacheKey, cached: CachedResponse) {
+        // A replaced entry keeps its eviction state, so that refreshing it doesn't make it a victim.
+        let (mut variants, hits) = self.detach(&key).map_or((Vec::new(), 0), |entry| (entry.variants, entry.hits));
+        variants.retain(|variant| variant.is_sibling_variant(&cached));
+        variants.push(cached);
+
+        let size = variants_size(&variants);
+        if size > self.max_bytes {
+            self.rejections += 1;
+            self.policy.remove(&key);
+            return;
+        }
+        while self.entries.len() >= self.max_entries || self.bytes + size > self.max_bytes {
+            let victim = match self.policy.victim() {
+                Some(victim) => victim,
+                None => break,
+            };
+            if victim == key {
+                self.policy.remove(&key);
+                continue;
+            }
+            if !self.policy.admit(&key, &victim) {
+                self.rejections += 1;
+                self.policy.remove(&key);
+                return;
+            }
+            self.remove(&victim);
+            self.evictions += 1;
+        }
+        self.bytes += size;
+        self.policy.insert(&key);
+        self.schedule(key, variants, hits);
+    }
+
+    pub fn remove(&mut self, key: &CacheKey) -> Option<Vec<CachedResponse>> {
+        self.take(key).map(|entry| entry.variants)
+    }
+
+    /// Removes every method and variant stored for the URL of `target`.
+    pub fn remove_url(&mut self, target: &CacheKey) {
+        for key in self.urls.same_url(target) {
+            self.remove(&key);
+        }
+    }
+
+    fn take(&mut self, key: &CacheKey) -> Option<Entry> {
+        let entry = self.detach(key)?;
+        self.policy.remove(key);
+        Some(entry)
+    }
+
+    // Removes the entry but leaves the key to the eviction policy.
+    fn detach(&mut self, key: &CacheKey) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.expiries.unschedule(&entry.expiry);
+        self.urls.remove(key);
+        self.bytes -= variants_size(&entry.variants);
+        Some(entry)
+    }
+
+    pub fn keys(&self) -> Vec<CacheKey> {
+        self.entries.keys().cloned().collect()
+    }
+
+    pub fn entries(&self, now: SystemTime) -> Vec<EntryInfo> {
+        self.entries
+            .iter()
+            .map(|(key, entry)| EntryInfo {
+                key: key.clone(),
+                hits: entry.hits,
+                variants: entry.variants.iter().map(|cached| VariantInfo::new(cached, cached.size(), now)).collect(),
+            })
+            .collect()
+    }
+
+    pub fn remove_expired(&mut self, now: SystemTime) {
+        while let Some(key) = self.expiries.pop_due(now) {
+            let (mut variants, hits) = match self.entries.remove(&key) {
+                Some(entry) => (entry.variants, entry.hits),
+                None => continue,
+            };
+            let size = variants_size(&variants);
+            variants.retain(|cached| cached.is_retained(now));
+            self.bytes -= size - variants_size(&variants);
+            if variants.is_empty() {
+                self.policy.remove(&key);
+                self.urls.remove(&key);
+            } else {
+                self.schedule(key, variants, hits);
+            }
+        }
+    }
+
+    fn schedule(&mut self, key: CacheKey, variants: Vec<CachedResponse>, hits: u64) {
+        let expiry = self.expiries.schedule(&key, &variants);
+        self.urls.insert(&key);
+        self.entries.insert(key, Entry { variants, expiry, hits });
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            bytes: self.bytes,
+            evictions: self.evictions,
+            rejections: self.rejections,
+        }
+    }
+}
+
+/// Keys ordered by when their last variant stops being retained, so expiry never scans.
+#[derive(Default)]
+pub(crate) struct Expiries {
+    queue: BTreeMap<Expiry, CacheKey>,
+    next: u64,
+}
+
+/// A key's place in `Expiries`, kept with its entry so that it can be unscheduled.
+pub(crate) type Expiry = (SystemTime, u64);
+
+impl Expiries {
+    /// Schedules `key` for when the last of `variants` stops being retained.
+    pub(crate) fn schedule<'a>(
+        &mut self,
+        key: &CacheKey,
+        variants: impl IntoIterator<Item = &'a CachedResponse>,
+    ) -> Expiry {
+        let deadline = variants.into_iter().map(CachedResponse::retained_until).max().unwrap_or(UNIX_EPOCH);
+        self.next += 1;
+        let expiry = (deadline, self.next);
+        self.queue.insert(expiry, key.clone());
+        expiry
+    }
+
+    pub(crate) fn unschedule(&mut self, expiry: &Expiry) {
+        self.queue.remove(expiry);
+    }
+
+    /// Takes out the next key that was due at `now`.
+    pub(crate) fn pop_due(&mut self, now: SystemTime) -> Option<CacheKey> {
+        let due = self.queue.first_entry().filter(|due| due.key().0 <= now)?;
+        Some(due.remove())
+    }
+}
+
+fn variants_size(variants: &[CachedResponse]) -> usize {
+    variants.iter().map(CachedResponse::size).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{body::Bytes, Request, Response};
+
+    use super::*;
+    use crate::{
+        cache_key::{DefaultKeyBuilder, KeyBuilder},
+        cached_response::FreshnessDefaults,
+        eviction::PolicyKind,
+    };
+
+    fn key(path: &str) -> CacheKey {
+        let (parts, _) = Request::get(path).body(()).unwrap().into_parts();
+        DefaultKeyBuilder::default().build(&parts, "http://example.com")
+    }
+
+    fn cached(body: &'static str) -> CachedResponse {
+        let (response, _) = Response::builder().header("cache-control", "max-age=60").body(()).unwrap().into_parts();
+        let now = SystemTime::now();
+        let defaults = FreshnessDefaults {
+            ttl: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+        };
+        let mut cached = CachedResponse::new(&HeaderMap::new(), &response, now, now, defaults);
+        cached.set_body(Bytes::from_static(body.as_bytes()));
+        cached
+    }
+
+    #[test]
+    fn replacing_an_entry_keeps_its_eviction_state() {
+        let mut cache = Cache::new(2, usize::MAX, PolicyKind::Lfu.build(2));
+        cache.insert(key("/hot"), cached("1"));
+        for _ in 0..3 {
+            assert!(cache.get(&key("/hot"), &HeaderMap::new()).is_some());
+        }
+        cache.insert(key("/cold"), cached("2"));
+        // A revalidated or refreshed entry is inserted again.
+        cache.insert(key("/hot"), cached("3"));
+        cache.insert(key("/new"), cached("4"));
+
+        assert!(cache.get(&key("/cold"), &HeaderMap::new()).is_none());
+        assert_eq!(cache.get(&key("/hot"), &HeaderMap::new()).unwrap().body(), "3");
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn a_replaced_entry_can_still_be_evicted() {
+        let mut cache = Cache::new(2, 2, PolicyKind::Lru.build(2));
+        cache.insert(key("/a"), cached("1"));
+        cache.insert(key("/b"), cached("2"));
+        // `/a` is still the policy's victim while it is replaced, which must not evict the new body.
+        cache.insert(key("/a"), cached("12"));
+        assert!(cache.get(&key("/b"), &HeaderMap::new()).is_none());
+        assert_eq!(cache.get(&key("/a"), &HeaderMap::new()).unwrap().body(), "12");
+        assert_eq!(cache.stats(), CacheStats { entries: 1, bytes: 2, evictions: 1, rejections: 0 });
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    cmp,
+    time::{Duration, SystemTime},
+};
+
+use hyper::{header, http::HeaderValue, HeaderMap, StatusCode};
+
+
+// RFC 9111 section 1.2.2: delta-seconds that overflow are treated as 2^31.
+const MAX_DELTA_SECONDS: u64 = 2_147_483_648;
+
+
+#[derive(Debug, Default, Clone)]
+pub struct CacheControl {
+    pub max_age: Option<Duration>,
+    pub s_maxage: Option<Duration>,
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub stale_if_error: Option<Duration>,
+    pub stale_while_revalidate: Option<Duration>,
+    /// Request only: how stale a response the client accepts, unlimited without an argument.
+    pub max_stale: Option<Duration>,
+    /// Request only: answer from the cache or not at all.
+    pub only_if_cached: bool,
+}
+
+impl CacheControl {
+    pub fn from_headers(headers: &HeaderMap<HeaderValue>) -> CacheControl {
+        let mut cache_control = CacheControl::default();
+        for value in headers.get_all(header::CACHE_CONTROL) {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            for directive in split_directives(value) {
+                let (name, argument) = match directive.split_once('=') {
+                    Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                    None => (directive.trim(), None),
+                };
+                match name.to_ascii_lowercase().as_str() {
+                    "max-age" => cache_control.max_age = argument.and_then(parse_delta_seconds),
+                    "s-maxage" => cache_control.s_maxage = argument.and_then(parse_delta_seconds),
+                    "no-store" => cache_control.no_store = true,
+                    "no-cache" => cache_control.no_cache = true,
+                    "private" => cache_control.private = true,
+                    "public" => cache_control.public = true,
+                    "must-revalidate" | "proxy-revalidate" => cache_control.must_revalidate = true,
+                    "stale-if-error" => cache_control.stale_if_error = argument.and_then(parse_delta_seconds),
+                    "stale-while-revalidate" => {
+                        cache_control.stale_while_revalidate = argument.and_then(parse_delta_seconds)
+                    },
+                    "max-stale" => {
+                        cache_control.max_stale = match argument {
+                            Some(argument) => parse_delta_seconds(argument),
+                            None => Some(Duration::from_secs(MAX_DELTA_SECONDS)),
+                        }
+                    },
+                    "only-if-cached" => cache_control.only_if_cached = true,
+                    _ => {}
+                }
+            }
+        }
+        cache_control
+    }
+}
+
+fn split_directives(value: &str) -> Vec<&str> {
+    let mut directives = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                directives.push(&value[start..i]);
+                start = i + 1;
+            },
+            _ => {}
+        }
+    }
+    directives.push(&value[start..]);
+    directives.into_iter().filter(|d| !d.trim().is_empty()).collect()
+}
+
+pub fn parse_delta_seconds(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let seconds = value.parse::<u64>().unwrap_or(MAX_DELTA_SECONDS);
+    Some(Duration::from_secs(cmp::min(seconds, MAX_DELTA_SECONDS)))
+}
+
+fn header_date(headers: &HeaderMap<HeaderValue>, name: header::HeaderName) -> Option<SystemTime> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+}
+
+/// Whether a shared cache may store the response at all (RFC 9111 section 3).
+pub fn is_storable(
+    request_headers: &HeaderMap<HeaderValue>,
+    status: StatusCode,
+    response_headers: &HeaderMap<HeaderValue>,
+) -> bool {
+    let cache_control = CacheControl::from_headers(response_headers);
+    if cache_control.no_store || cache_control.private || CacheControl::from_headers(request_headers).no_store {
+        return false;
+    }
+    // Partial content can't stand in for the full response, and a 304 has no content at all.
+    if status == StatusCode::PARTIAL_CONTENT || status == StatusCode::NOT_MODIFIED {
+        return false;
+    }
+    if request_headers.contains_key(header::AUTHORIZATION)
+        && !(cache_control.public || cache_control.must_revalidate || cache_control.s_maxage.is_some())
+    {
+        return false;
+    }
+    is_heuristically_cacheable(status)
+        || cache_control.public
+        || cache_control.max_age.is_some()
+        || cache_control.s_maxage.is_some()
+        || response_headers.contains_key(header::EXPIRES)
+}
+
+// RFC 9110 section 15.1: statuses that may be cached without explicit freshness information.
+fn is_heuristically_cacheable(status: StatusCode) -> bool {
+    matches!(
+        status.as_u16(),
+        200 | 203 | 204 | 206 | 300 | 301 | 308 | 404 | 405 | 410 | 414 | 501
+    )
+}
+
+/// Freshness lifetime per RFC 9111 section 4.2.1, falling back to `default_ttl`
+/// when the origin provides no explicit expiration time.
+pub fn freshness_lifetime(
+    headers: &HeaderMap<HeaderValue>,
+    cache_control: &CacheControl,
+    default_ttl: Duration,
+) -> Duration {
+    if cache_control.no_cache {
+        return Duration::ZERO;
+    }
+    if let Some(s_maxage) = cache_control.s_maxage {
+        return s_maxage;
+    }
+    if let Some(max_age) = cache_control.max_age {
+        return max_age;
+    }
+    if headers.contains_key(header::EXPIRES) {
+        // An invalid Expires value means the response is already stale.
+        return match (header_date(headers, header::EXPIRES), header_date(headers, header::DATE)) {
+            (Some(expires), Some(date)) => expires.duration_since(date).unwrap_or(Duration::ZERO),
+            (Some(expires), None) => expires.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO),
+            (None, _) => Duration::ZERO,
+        };
+    }
+    default_ttl
+}
+
+/// The corrected initial age of a response per RFC 9111 section 4.2.3.
+pub fn initial_age(
+    headers: &HeaderMap<HeaderValue>,
+    request_time: SystemTime,
+    response_time: SystemTime,
+) -> Duration {
+    let age_value = headers
+        .get(header::AGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_delta_seconds)
+        .unwrap_or(Duration::ZERO);
+    let apparent_age = header_date(headers, header::DATE)
+        .and_then(|date| response_time.duration_since(date).ok())
+        .unwrap_or(Duration::ZERO);
+    let response_delay = response_time.duration_since(request_time).unwrap_or(Duration::ZERO);
+    cmp::max(apparent_age, age_value + response_delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap<HeaderValue> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            let name = header::HeaderName::from_bytes(name.as_bytes()).unwrap();
+            headers.append(name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn parses_directives_across_headers() {
+        let cache_control = CacheControl::from_headers(&headers(&[
+            ("cache-control", "Public, MAX-AGE=60, s-maxage=\"120\""),
+            ("cache-control", "no-cache, must-revalidate, stale-if-error=30"),
+        ]));
+        assert!(cache_control.public);
+        assert_eq!(cache_control.max_age, Some(Duration::from_secs(60)));
+        assert_eq!(cache_control.s_maxage, Some(Duration::from_secs(120)));
+        assert!(cache_control.no_cache);
+        assert!(cache_control.must_revalidate);
+        assert_eq!(cache_control.stale_if_error, Some(Duration::from_secs(30)));
+        assert!(!cache_control.no_store);
+        assert!(!cache_control.private);
+    }
+
+    #[test]
+    fn ignores_commas_in_quoted_arguments() {
+        let cache_control =
+            CacheControl::from_headers(&headers(&[("cache-control", "no-cache=\"set-cookie, via\", max-age=5")]));
+        assert!(cache_control.no_cache);
+        assert_eq!(cache_control.max_age, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn invalid_and_overflowing_delta_seconds() {
+        let cache_control =
+            CacheControl::from_headers(&headers(&[("cache-control", "max-age=-1, s-maxage=99999999999999999999")]));
+        assert_eq!(cache_control.max_age, None);
+        assert_eq!(cache_control.s_maxage, Some(Duration::from_secs(MAX_DELTA_SECONDS)));
+        assert_eq!(parse_delta_seconds("1.5"), None);
+        assert_eq!(parse_delta_seconds(""), None);
+    }
+
+    #[test]
+    fn request_directives() {
+        let cache_control = CacheControl::from_headers(&headers(&[("cache-control", "max-stale, only-if-cached")]));
+        assert_eq!(cache_control.max_stale, Some(Duration::from_secs(MAX_DELTA_SECONDS)));
+        assert!(cache_control.only_if_cached);
+        let cache_control = CacheControl::from_headers(&headers(&[("cache-control", "max-stale=10")]));
+        assert_eq!(cache_control.max_stale, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn lifetime_prefers_s_maxage_then_max_age_then_expires() {
+        let default_ttl = Duration::from_secs(30);
+        let response = headers(&[
+            ("cache-control", "max-age=60, s-maxage=120"),
+            ("date", "Thu, 01 Jan 2026 00:00:00 GMT"),
+            ("expires", "Thu, 01 Jan 2026 00:10:00 GMT"),
+        ]);
+        let lifetime = |headers: &HeaderMap<HeaderValue>| {
+            freshness_lifetime(headers, &CacheControl::from_headers(headers), default_ttl)
+        };
+        assert_eq!(lifetime(&response), Duration::from_secs(120));
+
+        let mut response = response;
+        response.insert(header::CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        assert_eq!(lifetime(&response), Duration::from_secs(60));
+
+        response.remove(header::CACHE_CONTROL);
+        assert_eq!(lifetime(&response), Duration::from_secs(600));
+
+        response.insert(header::EXPIRES, HeaderValue::from_static("0"));
+        assert_eq!(lifetime(&response), Duration::ZERO);
+
+        assert_eq!(lifetime(&HeaderMap::new()), default_ttl);
+        assert_eq!(lifetime(&headers(&[("cache-control", "no-cache, max-age=60")])), Duration::ZERO);
+    }
+
+    #[test]
+    fn initial_age_takes_the_larger_estimate() {
+        let date = "Thu, 01 Jan 1970 00:01:40 GMT";
+        // Age plus the response delay exceeds the apparent age from Date.
+        let response = headers(&[("age", "50"), ("date", date)]);
+        assert_eq!(initial_age(&response, at(110), at(120)), Duration::from_secs(60));
+        // The apparent age from Date exceeds Age plus the response delay.
+        let response = headers(&[("age", "5"), ("date", date)]);
+        assert_eq!(initial_age(&response, at(118), at(120)), Duration::from_secs(20));
+        // A Date in the future counts as no apparent age.
+        let response = headers(&[("date", date)]);
+        assert_eq!(initial_age(&response, at(90), at(90)), Duration::ZERO);
+        assert_eq!(initial_age(&HeaderMap::new(), at(90), at(95)), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn storability_follows_the_response_and_request() {
+        let request = HeaderMap::new();
+        let storable = |request: &HeaderMap<HeaderValue>, status: u16, response: &[(&str, &str)]| {
+            is_storable(request, StatusCode::from_u16(status).unwrap(), &headers(response))
+        };
+        assert!(storable(&request, 200, &[]));
+        assert!(storable(&request, 404, &[]));
+        assert!(!storable(&request, 302, &[]));
+        assert!(storable(&request, 302, &[("cache-control", "max-age=60")]));
+        assert!(storable(&request, 500, &[("expires", "Thu, 01 Jan 2026 00:00:00 GMT")]));
+        assert!(!storable(&request, 200, &[("cache-control", "no-store")]));
+        assert!(!storable(&request, 200, &[("cache-control", "private, max-age=60")]));
+        assert!(!storable(&request, 206, &[("cache-control", "max-age=60")]));
+        assert!(!storable(&request, 304, &[("cache-control", "max-age=60")]));
+        assert!(!storable(&headers(&[("cache-control", "no-store")]), 200, &[]));
+
+        // Responses to authorized requests need explicit permission to be shared.
+        let authorized = headers(&[("authorization", "Bearer token")]);
+        assert!(!storable(&authorized, 200, &[("cache-control", "max-age=60")]));
+        assert!(storable(&authorized, 200, &[("cache-control", "public")]));
+        assert!(storable(&authorized, 200, &[("cache-control", "s-maxage=60")]));
+        assert!(storable(&authorized, 200, &[("cache-control", "must-revalidate")]));
+    }
+}
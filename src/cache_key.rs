@@ -0,0 +1,302 @@
+use std::collections::{HashMap, HashSet};
+
+use hyper::{
+    header::HeaderName,
+    http::{request, HeaderValue},
+    HeaderMap, Method, Uri,
+};
+use serde::{Deserialize, Deserializer};
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub method: Method,
+    pub origin: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(HeaderName, Option<String>)>,
+    /// SHA-256 of the request body, for methods whose responses depend on it.
+    pub body_digest: Option<[u8; 32]>,
+}
+
+impl CacheKey {
+    /// Whether both keys are for the same URL, whatever the method, headers or body.
+    pub fn same_url(&self, other: &CacheKey) -> bool {
+        self.origin == other.origin && self.path == other.path && self.query == other.query
+    }
+
+    pub fn url(&self) -> String {
+        match &self.query {
+            Some(query) => format!("{}{}?{}", self.origin, self.path, query),
+            None => format!("{}{}", self.origin, self.path),
+        }
+    }
+}
+
+/// Stored keys grouped by URL, so that invalidation finds every method and variant of a
+/// URL without scanning all keys.
+#[derive(Default)]
+pub struct UrlIndex {
+    urls: HashMap<String, HashSet<CacheKey>>,
+}
+
+impl UrlIndex {
+    pub fn insert(&mut self, key: &CacheKey) {
+        self.urls.entry(key.url()).or_default().insert(key.clone());
+    }
+
+    pub fn remove(&mut self, key: &CacheKey) {
+        let url = key.url();
+        if let Some(keys) = self.urls.get_mut(&url) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.urls.remove(&url);
+            }
+        }
+    }
+
+    /// The keys with the same URL as `target`.
+    pub fn same_url(&self, target: &CacheKey) -> Vec<CacheKey> {
+        self.urls.get(&target.url()).map_or_else(Vec::new, |keys| keys.iter().cloned().collect())
+    }
+}
+
+pub trait KeyBuilder: Send + Sync {
+    fn build(&self, parts: &request::Parts, origin: &str) -> CacheKey;
+}
+
+/// Keys on the normalized URL, configured as `[cache.key]` or per route as `[routes.key]`.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DefaultKeyBuilder {
+    /// Leave the query out of the key entirely.
+    pub ignore_query: bool,
+    /// Treat queries that only differ in parameter order as the same.
+    pub sort_query: bool,
+    /// Query parameters, such as tracking ones, left out of the key.
+    pub ignored_params: Vec<String>,
+    /// Request headers whose values become part of the key.
+    #[serde(deserialize_with = "deserialize_header_names")]
+    pub headers: Vec<HeaderName>,
+}
+
+impl KeyBuilder for DefaultKeyBuilder {
+    fn build(&self, parts: &request::Parts, origin: &str) -> CacheKey {
+        let query = if self.ignore_query {
+            None
+        } else {
+            parts.uri.query().and_then(|query| self.normalize_query(query))
+        };
+        CacheKey {
+            method: parts.method.clone(),
+            origin: normalize_origin(origin),
+            path: normalize_path(parts.uri.path()),
+            query,
+            headers: self.headers
+                .iter()
+                .map(|name| (name.clone(), header_value(&parts.headers, name)))
+                .collect(),
+            body_digest: None,
+        }
+    }
+}
+
+impl DefaultKeyBuilder {
+    fn normalize_query(&self, query: &str) -> Option<String> {
+        let mut params: Vec<&str> = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .filter(|param| {
+                let name = param.split('=').next().unwrap_or(param);
+                !self.ignored_params.iter().any(|ignored| ignored == name)
+            })
+            .collect();
+        if self.sort_query {
+            params.sort_unstable();
+        }
+        if params.is_empty() {
+            None
+        } else {
+            Some(params.iter().map(|param| normalize_percent_encoding(param)).collect::<Vec<_>>().join("&"))
+        }
+    }
+}
+
+fn deserialize_header_names<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<HeaderName>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|name| {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| serde::de::Error::custom(format!("invalid header name `{}`", name)))
+        })
+        .collect()
+}
+
+/// All field lines for `name` combined into one value, as used for key and Vary matching.
+pub fn header_value(headers: &HeaderMap<HeaderValue>, name: &HeaderName) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::trim)
+        .collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+pub fn normalize_origin(origin: &str) -> String {
+    let uri = match origin.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return origin.to_ascii_lowercase(),
+    };
+    let scheme = uri.scheme_str().unwrap_or("http").to_ascii_lowercase();
+    let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+    match uri.port_u16() {
+        Some(80) if scheme == "http" => format!("{}://{}", scheme, host),
+        Some(443) if scheme == "https" => format!("{}://{}", scheme, host),
+        Some(port) => format!("{}://{}:{}", scheme, host, port),
+        None => format!("{}://{}", scheme, host),
+    }
+}
+
+// RFC 3986 section 6.2.2: percent-encoding normalization, then dot-segment removal
+// (section 5.2.4), so that encoded dots are removed too.
+pub(crate) fn normalize_path(path: &str) -> String {
+    let normalized = normalize_percent_encoding(path);
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in normalized.split('/').skip(1) {
+        match segment {
+            "." => {},
+            ".." => {
+                segments.pop();
+            },
+            _ => segments.push(segment),
+        }
+    }
+    if matches!(normalized.rsplit('/').next(), Some(".") | Some("..")) {
+        segments.push("");
+    }
+    format!("/{}", segments.join("/"))
+}
+
+// RFC 3986 section 6.2.2: uppercase hex digits and decode unreserved characters.
+fn normalize_percent_encoding(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut normalized = String::with_capacity(value.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() && bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit() {
+            let decoded = u8::from_str_radix(&value[i + 1..i + 3], 16).unwrap();
+            if decoded.is_ascii_alphanumeric() || b"-._~".contains(&decoded) {
+                normalized.push(decoded as char);
+            } else {
+                normalized.push('%');
+                normalized.push_str(&value[i + 1..i + 3].to_ascii_uppercase());
+            }
+            i += 3;
+        } else {
+            normalized.push(bytes[i] as char);
+            i += 1;
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Request;
+
+    fn parts(uri: &str) -> request::Parts {
+        Request::get(uri).body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn removes_dot_segments() {
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("/a/b/c/./../../g"), "/a/g");
+        assert_eq!(normalize_path("/a/./b/."), "/a/b/");
+        assert_eq!(normalize_path("/a/b/.."), "/a/");
+        assert_eq!(normalize_path("/../../a"), "/a");
+        assert_eq!(normalize_path("/a//b"), "/a//b");
+        assert_eq!(normalize_path("/a/.hidden/..b"), "/a/.hidden/..b");
+    }
+
+    #[test]
+    fn normalizes_percent_encoding() {
+        assert_eq!(normalize_percent_encoding("%7euser%2Fdocs%2f"), "~user%2Fdocs%2F");
+        assert_eq!(normalize_percent_encoding("%41%2d%5F%3a"), "A-_%3A");
+        assert_eq!(normalize_percent_encoding("100%"), "100%");
+        assert_eq!(normalize_percent_encoding("%4"), "%4");
+        assert_eq!(normalize_percent_encoding("%zz%20"), "%zz%20");
+        // Encoded dots are decoded before dot segments are removed.
+        assert_eq!(normalize_path("/a/%2E%2E/b"), "/b");
+        assert_eq!(normalize_path("/a/%2e/b/%2E"), "/a/b/");
+    }
+
+    #[test]
+    fn normalizes_origins() {
+        assert_eq!(normalize_origin("HTTP://Example.COM:80"), "http://example.com");
+        assert_eq!(normalize_origin("https://example.com:443"), "https://example.com");
+        assert_eq!(normalize_origin("https://example.com:80"), "https://example.com:80");
+        assert_eq!(normalize_origin("http://[::1]:8080"), "http://[::1]:8080");
+    }
+
+    #[test]
+    fn builds_keys_from_normalized_requests() {
+        let builder = DefaultKeyBuilder {
+            sort_query: true,
+            ignored_params: vec!["utm_source".to_owned()],
+            ..DefaultKeyBuilder::default()
+        };
+        let key = builder.build(&parts("/a/./b/../c?z=1&utm_source=x&&a=%7e"), "HTTPS://Example.com:443");
+        assert_eq!(key.origin, "https://example.com");
+        assert_eq!(key.path, "/a/c");
+        assert_eq!(key.query.as_deref(), Some("a=~&z=1"));
+        assert_eq!(key.url(), "https://example.com/a/c?a=~&z=1");
+
+        assert_eq!(builder.build(&parts("/?utm_source=x"), "https://example.com").query, None);
+        let ignore_query = DefaultKeyBuilder { ignore_query: true, ..DefaultKeyBuilder::default() };
+        assert_eq!(ignore_query.build(&parts("/?a=1"), "https://example.com").query, None);
+    }
+
+    #[test]
+    fn keys_include_configured_headers() {
+        let builder = DefaultKeyBuilder {
+            headers: vec![HeaderName::from_static("accept-language")],
+            ..DefaultKeyBuilder::default()
+        };
+        let mut request = parts("/");
+        request.headers.append("accept-language", HeaderValue::from_static("en "));
+        request.headers.append("accept-language", HeaderValue::from_static("de"));
+        let key = builder.build(&request, "https://example.com");
+        assert_eq!(key.headers, vec![(HeaderName::from_static("accept-language"), Some("en, de".to_owned()))]);
+        assert_eq!(builder.build(&parts("/"), "https://example.com").headers[0].1, None);
+    }
+
+    #[test]
+    fn url_index_groups_keys_by_url() {
+        let builder = DefaultKeyBuilder::default();
+        let get = builder.build(&parts("/a?x=1"), "https://example.com");
+        let head = CacheKey { method: Method::HEAD, ..get.clone() };
+        let post = CacheKey { method: Method::POST, body_digest: Some([1; 32]), ..get.clone() };
+        let other = builder.build(&parts("/a?x=2"), "https://example.com");
+        let mut index = UrlIndex::default();
+        for key in [&get, &head, &post, &other] {
+            index.insert(key);
+        }
+        let mut keys = index.same_url(&get);
+        keys.sort_by_key(|key| key.method.to_string());
+        assert_eq!(keys, vec![get.clone(), head.clone(), post.clone()]);
+
+        index.remove(&head);
+        index.remove(&post);
+        assert_eq!(index.same_url(&get), vec![get.clone()]);
+        index.remove(&get);
+        assert!(index.same_url(&get).is_empty());
+        assert!(index.urls.len() == 1);
+    }
+}
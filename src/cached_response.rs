@@ -0,0 +1,437 @@
+use std::time::{Duration, SystemTime};
+
+use hyper::{
+    body::Bytes,
+    header::{self, HeaderName},
+    http::{response, HeaderValue},
+    Body, HeaderMap, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache_control::{self, CacheControl},
+    cache_key,
+};
+
+
+const STALE_RETENTION: Duration = Duration::new(600, 0);
+
+pub const RESPONSE_IS_STALE: &str = "110 - \"Response is Stale\"";
+pub const REVALIDATION_FAILED: &str = "111 - \"Revalidation Failed\"";
+
+
+/// Values applied when the origin's response doesn't say otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct FreshnessDefaults {
+    pub ttl: Duration,
+    pub stale_if_error: Duration,
+    pub stale_while_revalidate: Duration,
+}
+
+/// A `CachedResponse` without its body, as written to the disk index.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Metadata {
+    status: u16,
+    headers: Vec<(String, String)>,
+    stored_at: SystemTime,
+    initial_age: Duration,
+    lifetime: Duration,
+    stale_if_error: Duration,
+    stale_while_revalidate: Duration,
+    must_revalidate: bool,
+    vary: Vec<(String, Option<String>)>,
+}
+
+#[derive(Clone)]
+pub struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap<HeaderValue>,
+    body: Bytes,
+    stored_at: SystemTime,
+    initial_age: Duration,
+    lifetime: Duration,
+    stale_if_error: Duration,
+    stale_while_revalidate: Duration,
+    must_revalidate: bool,
+    vary: Vec<(HeaderName, Option<String>)>,
+}
+
+impl CachedResponse {
+    pub fn new(
+        request_headers: &HeaderMap<HeaderValue>,
+        parts: &response::Parts,
+        request_time: SystemTime,
+        response_time: SystemTime,
+        defaults: FreshnessDefaults,
+    ) -> CachedResponse {
+        let mut cached = CachedResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: Bytes::new(),
+            stored_at: response_time,
+            initial_age: Duration::ZERO,
+            lifetime: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+            must_revalidate: false,
+            vary: vary_header_names(&parts.headers)
+                .into_iter()
+                .map(|name| {
+                    let value = cache_key::header_value(request_headers, &name);
+                    (name, value)
+                })
+                .collect(),
+        };
+        cached.update_freshness(request_time, response_time, defaults);
+        cached
+    }
+
+    /// `None` when a header value isn't a visible ASCII string and can't be written out.
+    pub fn metadata(&self) -> Option<Metadata> {
+        let mut headers = Vec::with_capacity(self.headers.len());
+        for (name, value) in &self.headers {
+            headers.push((name.as_str().to_owned(), value.to_str().ok()?.to_owned()));
+        }
+        Some(Metadata {
+            status: self.status.as_u16(),
+            headers,
+            stored_at: self.stored_at,
+            initial_age: self.initial_age,
+            lifetime: self.lifetime,
+            stale_if_error: self.stale_if_error,
+            stale_while_revalidate: self.stale_while_revalidate,
+            must_revalidate: self.must_revalidate,
+            vary: self.vary.iter().map(|(name, value)| (name.as_str().to_owned(), value.clone())).collect(),
+        })
+    }
+
+    /// Restores a response from its metadata, with an empty body.
+    pub fn from_metadata(metadata: Metadata) -> Option<CachedResponse> {
+        let mut headers = HeaderMap::with_capacity(metadata.headers.len());
+        for (name, value) in metadata.headers {
+            headers.append(HeaderName::try_from(name).ok()?, HeaderValue::try_from(value).ok()?);
+        }
+        let mut vary = Vec::with_capacity(metadata.vary.len());
+        for (name, value) in metadata.vary {
+            vary.push((HeaderName::try_from(name).ok()?, value));
+        }
+        Some(CachedResponse {
+            status: StatusCode::from_u16(metadata.status).ok()?,
+            headers,
+            body: Bytes::new(),
+            stored_at: metadata.stored_at,
+            initial_age: metadata.initial_age,
+            lifetime: metadata.lifetime,
+            stale_if_error: metadata.stale_if_error,
+            stale_while_revalidate: metadata.stale_while_revalidate,
+            must_revalidate: metadata.must_revalidate,
+            vary,
+        })
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap<HeaderValue> {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    pub fn size(&self) -> usize {
+        self.body.len()
+    }
+
+    pub fn set_body(&mut self, body: Bytes) {
+        self.body = body;
+    }
+
+    pub fn update_freshness(&mut self, request_time: SystemTime, response_time: SystemTime, defaults: FreshnessDefaults) {
+        let cache_control = CacheControl::from_headers(&self.headers);
+        self.stored_at = response_time;
+        self.initial_age = cache_control::initial_age(&self.headers, request_time, response_time);
+        self.lifetime = cache_control::freshness_lifetime(&self.headers, &cache_control, defaults.ttl);
+        self.must_revalidate = cache_control.must_revalidate;
+        // RFC 5861 doesn't override must-revalidate, so neither window applies then.
+        if cache_control.must_revalidate || cache_control.no_cache {
+            self.stale_if_error = Duration::ZERO;
+            self.stale_while_revalidate = Duration::ZERO;
+        } else {
+            self.stale_if_error = cache_control.stale_if_error.unwrap_or(defaults.stale_if_error);
+            self.stale_while_revalidate =
+                cache_control.stale_while_revalidate.unwrap_or(defaults.stale_while_revalidate);
+        }
+    }
+
+    pub fn has_validators(&self) -> bool {
+        self.headers.contains_key(header::ETAG) || self.headers.contains_key(header::LAST_MODIFIED)
+    }
+
+    pub fn add_validators(&self, headers: &mut HeaderMap<HeaderValue>) {
+        headers.remove(header::IF_NONE_MATCH);
+        headers.remove(header::IF_MODIFIED_SINCE);
+        if let Some(etag) = self.headers.get(header::ETAG) {
+            headers.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.headers.get(header::LAST_MODIFIED) {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    // RFC 9111 section 4.3.4: headers from a 304 replace the stored ones.
+    pub fn merge_not_modified(&mut self, headers: &HeaderMap<HeaderValue>) {
+        for name in headers.keys() {
+            if name == header::CONTENT_LENGTH || name == header::TRANSFER_ENCODING {
+                continue;
+            }
+            self.headers.remove(name);
+            for value in headers.get_all(name) {
+                self.headers.append(name.clone(), value.clone());
+            }
+        }
+    }
+
+    pub fn to_response(&self, now: SystemTime) -> Response<Body> {
+        self.to_response_with_body(Body::from(self.body.clone()), now)
+    }
+
+    pub fn to_response_with_body(&self, body: Body, now: SystemTime) -> Response<Body> {
+        let mut response = Response::builder()
+            .status(self.status)
+            .body(body)
+            .unwrap();
+        *response.headers_mut() = self.headers.clone();
+        response.headers_mut().insert(header::AGE, HeaderValue::from(self.age(now).as_secs()));
+        response
+    }
+
+    pub fn age(&self, now: SystemTime) -> Duration {
+        self.initial_age + now.duration_since(self.stored_at).unwrap_or(Duration::ZERO)
+    }
+
+    /// How long the entry stays fresh, zero once it is stale.
+    pub fn time_to_live(&self, now: SystemTime) -> Duration {
+        self.lifetime.saturating_sub(self.age(now))
+    }
+
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        self.age(now) < self.lifetime
+    }
+
+    /// Whether the entry can answer a request with these directives without contacting the
+    /// upstream (RFC 9111 section 5.2.1).
+    pub fn satisfies(&self, request: &CacheControl, now: SystemTime) -> bool {
+        let age = self.age(now);
+        if request.no_cache || request.max_age.is_some_and(|max_age| age > max_age) {
+            return false;
+        }
+        if age < self.lifetime {
+            return true;
+        }
+        !self.must_revalidate && request.max_stale.is_some_and(|max_stale| age - self.lifetime <= max_stale)
+    }
+
+    /// Whether the request selects this response under the stored `Vary` header.
+    pub fn matches_vary(&self, request_headers: &HeaderMap<HeaderValue>) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| cache_key::header_value(request_headers, name) == *value)
+    }
+
+    /// Whether `other` is stored under the same Vary header names but for different request values.
+    pub fn is_sibling_variant(&self, other: &CachedResponse) -> bool {
+        self.vary.len() == other.vary.len()
+            && self.vary.iter().zip(&other.vary).all(|((name, _), (other_name, _))| name == other_name)
+            && self.vary != other.vary
+    }
+
+    /// Whether the entry may stand in for an upstream that failed or returned a 5xx.
+    pub fn is_usable_if_error(&self, now: SystemTime) -> bool {
+        self.age(now) < self.lifetime + self.stale_if_error
+    }
+
+    /// Whether the entry may be served while it is refreshed in the background.
+    pub fn is_usable_while_revalidating(&self, now: SystemTime) -> bool {
+        self.age(now) < self.lifetime + self.stale_while_revalidate
+    }
+
+    pub fn mark_stale(&mut self, warning: &'static str) {
+        self.headers.append(header::WARNING, HeaderValue::from_static(warning));
+    }
+
+    /// When the entry stops being worth keeping: it can't be served fresh, stale within one
+    /// of its windows, or revalidated.
+    pub fn retained_until(&self) -> SystemTime {
+        let mut retention = self.lifetime + self.stale_if_error.max(self.stale_while_revalidate);
+        if self.has_validators() {
+            retention = retention.max(self.lifetime + STALE_RETENTION);
+        }
+        self.stored_at + retention.saturating_sub(self.initial_age)
+    }
+
+    pub fn is_retained(&self, now: SystemTime) -> bool {
+        now < self.retained_until()
+    }
+}
+
+/// `Vary: *` means no subsequent request can be matched, so the response is not worth storing.
+pub fn varies_on_everything(headers: &HeaderMap<HeaderValue>) -> bool {
+    vary_header_names(headers).iter().any(|name| name == "*")
+}
+
+fn vary_header_names(headers: &HeaderMap<HeaderValue>) -> Vec<HeaderName> {
+    headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(headers: &[(&str, &str)]) -> CachedResponse {
+        cached_at(headers, SystemTime::now())
+    }
+
+    fn cached_at(headers: &[(&str, &str)], time: SystemTime) -> CachedResponse {
+        let mut response = Response::builder();
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        let (parts, _) = response.body(()).unwrap().into_parts();
+        let defaults = FreshnessDefaults {
+            ttl: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+        };
+        CachedResponse::new(&HeaderMap::new(), &parts, time, time, defaults)
+    }
+
+    fn request(cache_control: &str) -> CacheControl {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_str(cache_control).unwrap());
+        CacheControl::from_headers(&headers)
+    }
+
+    #[test]
+    fn replaces_the_clients_validators_with_the_stored_ones() {
+        let mut request = HeaderMap::new();
+        request.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"client\""));
+        request.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_static("Mon, 01 Jan 2024 00:00:00 GMT"));
+        cached(&[("etag", "\"v1\"")]).add_validators(&mut request);
+        assert_eq!(request[header::IF_NONE_MATCH], "\"v1\"");
+        assert!(!request.contains_key(header::IF_MODIFIED_SINCE));
+
+        let last_modified = "Tue, 02 Jan 2024 00:00:00 GMT";
+        let stored = cached(&[("last-modified", last_modified)]);
+        assert!(stored.has_validators());
+        stored.add_validators(&mut request);
+        assert!(!request.contains_key(header::IF_NONE_MATCH));
+        assert_eq!(request[header::IF_MODIFIED_SINCE], last_modified);
+        assert!(!cached(&[("cache-control", "max-age=60")]).has_validators());
+    }
+
+    #[test]
+    fn merges_304_headers_into_the_stored_response() {
+        let mut stored = cached(&[("etag", "\"v1\""), ("content-length", "6"), ("x-kept", "1"), ("link", "<a>")]);
+        let mut not_modified = HeaderMap::new();
+        not_modified.insert(header::ETAG, HeaderValue::from_static("\"v2\""));
+        not_modified.insert(header::CONTENT_LENGTH, HeaderValue::from_static("0"));
+        not_modified.append(header::LINK, HeaderValue::from_static("<b>"));
+        not_modified.append(header::LINK, HeaderValue::from_static("<c>"));
+        stored.merge_not_modified(&not_modified);
+
+        let headers = stored.headers();
+        assert_eq!(headers[header::ETAG], "\"v2\"");
+        assert_eq!(headers[header::CONTENT_LENGTH], "6");
+        assert_eq!(headers["x-kept"], "1");
+        assert_eq!(headers.get_all(header::LINK).iter().collect::<Vec<_>>(), ["<b>", "<c>"]);
+    }
+
+    #[test]
+    fn satisfies_the_requests_freshness_directives() {
+        let stored = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let after = |seconds| stored + Duration::from_secs(seconds);
+        let cached = cached_at(&[("cache-control", "max-age=60")], stored);
+
+        assert!(cached.satisfies(&CacheControl::default(), after(59)));
+        assert!(!cached.satisfies(&CacheControl::default(), after(60)));
+        assert!(!cached.satisfies(&request("no-cache"), after(0)));
+        assert!(cached.satisfies(&request("max-age=30"), after(30)));
+        assert!(!cached.satisfies(&request("max-age=30"), after(31)));
+
+        // max-stale accepts stale responses, up to its argument if there is one.
+        assert!(cached.satisfies(&request("max-stale=10"), after(70)));
+        assert!(!cached.satisfies(&request("max-stale=10"), after(71)));
+        assert!(cached.satisfies(&request("max-stale"), after(100_000)));
+        let must_revalidate = cached_at(&[("cache-control", "max-age=60, must-revalidate")], stored);
+        assert!(!must_revalidate.satisfies(&request("max-stale"), after(61)));
+        // only-if-cached alone doesn't make a stale response acceptable.
+        assert!(!cached.satisfies(&request("only-if-cached"), after(61)));
+    }
+
+    #[test]
+    fn stale_if_error_window_and_retention() {
+        let stored = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let after = |seconds| stored + Duration::from_secs(seconds);
+        let cached = cached_at(&[("cache-control", "max-age=60, stale-if-error=30")], stored);
+        assert!(cached.is_usable_if_error(after(89)));
+        assert!(!cached.is_usable_if_error(after(90)));
+        assert!(!cached.is_usable_while_revalidating(after(60)));
+        assert_eq!(cached.retained_until(), after(90));
+        assert!(cached.is_retained(after(89)) && !cached.is_retained(after(90)));
+
+        // Age already spent upstream shortens every window.
+        let aged = cached_at(&[("cache-control", "max-age=60, stale-if-error=30"), ("age", "10")], stored);
+        assert!(!aged.is_usable_if_error(after(80)));
+        assert_eq!(aged.retained_until(), after(80));
+        // Entries with validators are kept for revalidation after their windows have passed.
+        let validated = cached_at(&[("cache-control", "max-age=60, stale-if-error=30"), ("etag", "\"v1\"")], stored);
+        assert_eq!(validated.retained_until(), after(60) + STALE_RETENTION);
+        // must-revalidate rules out serving stale responses.
+        let strict = cached_at(&[("cache-control", "max-age=60, stale-if-error=30, must-revalidate")], stored);
+        assert!(!strict.is_usable_if_error(after(61)));
+        assert_eq!(strict.retained_until(), after(60));
+    }
+
+    #[test]
+    fn origin_windows_override_the_configured_defaults() {
+        let (parts, _) = Response::builder()
+            .header("cache-control", "max-age=60, stale-while-revalidate=5")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let stored = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let defaults = FreshnessDefaults {
+            ttl: Duration::from_secs(30),
+            stale_if_error: Duration::from_secs(300),
+            stale_while_revalidate: Duration::from_secs(100),
+        };
+        let cached = CachedResponse::new(&HeaderMap::new(), &parts, stored, stored, defaults);
+        assert!(cached.is_usable_if_error(stored + Duration::from_secs(359)));
+        assert!(!cached.is_usable_while_revalidating(stored + Duration::from_secs(65)));
+        assert_eq!(cached.retained_until(), stored + Duration::from_secs(360));
+    }
+
+    #[test]
+    fn stale_while_revalidate_window() {
+        let stored = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let after = |seconds| stored + Duration::from_secs(seconds);
+        let cache_control = "max-age=60, stale-while-revalidate=30, stale-if-error=10";
+        let cached = cached_at(&[("cache-control", cache_control)], stored);
+        assert!(cached.is_usable_while_revalidating(after(89)));
+        assert!(!cached.is_usable_while_revalidating(after(90)));
+        assert!(!cached.is_usable_if_error(after(70)));
+        // Kept for the longer of the two windows.
+        assert_eq!(cached.retained_until(), after(90));
+        let no_cache = cached_at(&[("cache-control", "max-age=60, stale-while-revalidate=30, no-cache")], stored);
+        assert!(!no_cache.is_usable_while_revalidating(after(0)));
+    }
+}
@@ -0,0 +1,553 @@
+use std::{
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::{
+    access::{self, Network, OriginPattern},
+    cache_key::DefaultKeyBuilder,
+    eviction::PolicyKind,
+    router::Route,
+};
+
+
+#[derive(Parser, Debug)]
+#[command(about = "Caching HTTP proxy")]
+pub struct Cli {
+    /// Path to a TOML configuration file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, may be repeated
+    #[arg(long = "listen", value_name = "ADDR")]
+    pub listen: Vec<SocketAddr>,
+
+    /// Freshness lifetime for responses without explicit expiration, e.g. `30s`
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub default_ttl: Option<Duration>,
+
+    /// Maximum number of cached URLs
+    #[arg(long)]
+    pub max_entries: Option<usize>,
+
+    /// Maximum total size of cached bodies in bytes
+    #[arg(long)]
+    pub max_bytes: Option<usize>,
+
+    /// Directory for the persistent cache tier, enables it with default limits
+    #[arg(long, value_name = "DIR")]
+    pub disk_cache: Option<PathBuf>,
+
+    /// Time allowed to establish an upstream connection
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub upstream_connect_timeout: Option<Duration>,
+
+    /// Time allowed for an upstream to start responding
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub upstream_request_timeout: Option<Duration>,
+
+    #[arg(long, value_enum)]
+    pub log_level: Option<LogLevel>,
+
+    /// Log as human-readable lines or as JSON objects, one per line
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Let clients pick the upstream with the `Origin` header when no route matches
+    #[arg(long)]
+    pub origin_header: bool,
+
+    /// Upstream origin pattern clients may request, e.g. `https://*.example.com`, may be repeated
+    #[arg(long = "allowed-origin", value_name = "PATTERN")]
+    pub allowed_origins: Vec<OriginPattern>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    /// Serve HTTPS rather than plain HTTP on the listen addresses.
+    pub tls: Option<TlsConfig>,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub access_log: AccessLogConfig,
+    pub routes: Vec<Route>,
+    pub origin_header: bool,
+    pub allowed_origins: Vec<OriginPattern>,
+    /// Networks that upstreams picked with the `Origin` header must not be in.
+    pub denied_networks: Vec<Network>,
+    pub cache: CacheConfig,
+    pub upstream: UpstreamConfig,
+    pub forwarding: ForwardingConfig,
+    /// Listener for cache inspection and purging, disabled unless configured.
+    pub admin: Option<AdminConfig>,
+    /// Trace export over OTLP/HTTP, disabled unless configured.
+    pub otlp: Option<OtlpConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    #[serde(with = "humantime_serde")]
+    pub default_ttl: Duration,
+    /// How long past expiry a response may be served when the upstream fails, unless the
+    /// origin sets its own `stale-if-error`.
+    #[serde(with = "humantime_serde")]
+    pub stale_if_error: Duration,
+    /// How long past expiry a response may be served while it is refreshed in the background,
+    /// unless the origin sets its own `stale-while-revalidate`.
+    #[serde(with = "humantime_serde")]
+    pub stale_while_revalidate: Duration,
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    pub max_body_size: usize,
+    /// Also cache POST responses, keyed on a digest of the request body.
+    pub cache_post: bool,
+    /// Store the headers of HEAD responses for URLs without a cached GET response.
+    pub head_metadata: bool,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub eviction_policy: PolicyKind,
+    #[serde(with = "humantime_serde")]
+    pub coalesce_timeout: Duration,
+    /// Independently locked partitions of the memory cache, each with an equal share of the limits.
+    pub shards: usize,
+    /// Keeps responses across restarts. Consulted when the memory tier misses.
+    pub disk: Option<DiskConfig>,
+    /// How requests are keyed, unless their route says otherwise.
+    pub key: DefaultKeyBuilder,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskConfig {
+    pub path: PathBuf,
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub eviction_policy: PolicyKind,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    #[serde(with = "humantime_serde")]
+    pub connect_timeout: Duration,
+    #[serde(with = "humantime_serde")]
+    pub request_timeout: Duration,
+}
+
+/// PEM files with the certificate chain and its private key. Clients can negotiate HTTP/2
+/// with ALPN.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// What upstreams are told about the client a request came from.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardingConfig {
+    pub headers: ForwardedHeaders,
+    /// Peers, such as a load balancer in front of the proxy, whose forwarding headers are
+    /// extended rather than replaced.
+    pub trusted_proxies: Vec<Network>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub listen: SocketAddr,
+    /// Clients must send `Authorization: Bearer <token>`.
+    pub token: String,
+}
+
+/// One line per request, written once its response body has been sent.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub enabled: bool,
+    /// Write to this file instead of the main log, rotating it as configured.
+    pub path: Option<PathBuf>,
+    pub rotation: LogRotation,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// The collector's traces URL.
+    pub endpoint: String,
+    pub service_name: String,
+    /// Share of new traces recorded, traces started upstream of the proxy keep their decision.
+    pub sample_ratio: f64,
+}
+
+#[derive(Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+/// `X-Forwarded-For`, `-Proto` and `-Host`, the RFC 7239 `Forwarded` header, both or neither.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeaders {
+    XForwarded,
+    Forwarded,
+    Both,
+    None,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 3000))],
+            tls: None,
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Human,
+            access_log: AccessLogConfig::default(),
+            routes: Vec::new(),
+            origin_header: false,
+            allowed_origins: Vec::new(),
+            denied_networks: access::private_networks(),
+            cache: CacheConfig::default(),
+            upstream: UpstreamConfig::default(),
+            forwarding: ForwardingConfig::default(),
+            admin: None,
+            otlp: None,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            default_ttl: Duration::new(30, 0),
+            stale_if_error: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+            max_entries: 10_000,
+            max_bytes: 256 * 1024 * 1024,
+            max_body_size: 10 * 1024 * 1024,
+            cache_post: false,
+            head_metadata: false,
+            eviction_policy: PolicyKind::Lru,
+            coalesce_timeout: Duration::new(5, 0),
+            shards: 16,
+            disk: None,
+            key: DefaultKeyBuilder::default(),
+        }
+    }
+}
+
+impl Default for DiskConfig {
+    fn default() -> DiskConfig {
+        DiskConfig {
+            path: PathBuf::from("cache"),
+            max_entries: 100_000,
+            max_bytes: 1024 * 1024 * 1024,
+            eviction_policy: PolicyKind::Lru,
+        }
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> AccessLogConfig {
+        AccessLogConfig {
+            enabled: true,
+            path: None,
+            rotation: LogRotation::Daily,
+        }
+    }
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> ForwardingConfig {
+        ForwardingConfig {
+            headers: ForwardedHeaders::XForwarded,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> AdminConfig {
+        AdminConfig {
+            listen: SocketAddr::from(([127, 0, 0, 1], 3001)),
+            token: String::new(),
+        }
+    }
+}
+
+impl Default for OtlpConfig {
+    fn default() -> OtlpConfig {
+        OtlpConfig {
+            endpoint: "http://127.0.0.1:4318/v1/traces".to_owned(),
+            service_name: "proxy-with-cache".to_owned(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> UpstreamConfig {
+        UpstreamConfig {
+            connect_timeout: Duration::new(10, 0),
+            request_timeout: Duration::new(30, 0),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid { key: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, error) => write!(f, "can't read {}: {}", path.display(), error),
+            ConfigError::Parse(path, error) => write!(f, "invalid configuration in {}: {}", path.display(), error),
+            ConfigError::Invalid { key, message } => write!(f, "invalid configuration `{}`: {}", key, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the configuration file named on the command line, if any, and applies flag overrides.
+    pub fn load(cli: Cli) -> Result<Config, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        if !cli.listen.is_empty() {
+            config.listen = cli.listen;
+        }
+        if cli.origin_header {
+            config.origin_header = true;
+        }
+        if !cli.allowed_origins.is_empty() {
+            config.allowed_origins = cli.allowed_origins;
+        }
+        if let Some(log_level) = cli.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(log_format) = cli.log_format {
+            config.log_format = log_format;
+        }
+        if let Some(default_ttl) = cli.default_ttl {
+            config.cache.default_ttl = default_ttl;
+        }
+        if let Some(max_entries) = cli.max_entries {
+            config.cache.max_entries = max_entries;
+        }
+        if let Some(max_bytes) = cli.max_bytes {
+            config.cache.max_bytes = max_bytes;
+        }
+        if let Some(path) = cli.disk_cache {
+            config.cache.disk.get_or_insert_with(DiskConfig::default).path = path;
+        }
+        if let Some(connect_timeout) = cli.upstream_connect_timeout {
+            config.upstream.connect_timeout = connect_timeout;
+        }
+        if let Some(request_timeout) = cli.upstream_request_timeout {
+            config.upstream.request_timeout = request_timeout;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|error| ConfigError::Read(path.to_owned(), error))?;
+        toml::from_str(&contents).map_err(|error| ConfigError::Parse(path.to_owned(), error))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, message: &str| {
+            Err(ConfigError::Invalid { key: key.to_owned(), message: message.to_owned() })
+        };
+        if self.listen.is_empty() {
+            return invalid("listen", "at least one address is required");
+        }
+        for (i, route) in self.routes.iter().enumerate() {
+            if let Err(message) = route.validate() {
+                return invalid(&format!("routes[{}]", i), &message);
+            }
+            if self.routes[..i].iter().any(|other| other.name == route.name) {
+                return invalid(&format!("routes[{}].name", i), &format!("duplicate route name `{}`", route.name));
+            }
+        }
+        if self.origin_header && self.allowed_origins.is_empty() {
+            return invalid("allowed_origins", "must list the origins clients may request when origin_header is on");
+        }
+        if self.cache.max_entries == 0 {
+            return invalid("cache.max_entries", "must be greater than 0");
+        }
+        if self.cache.max_bytes == 0 {
+            return invalid("cache.max_bytes", "must be greater than 0");
+        }
+        if self.cache.shards == 0 {
+            return invalid("cache.shards", "must be greater than 0");
+        }
+        if self.cache.max_body_size > self.cache.max_bytes / self.cache.shards {
+            return invalid("cache.max_body_size", "must not exceed cache.max_bytes / cache.shards");
+        }
+        if let Some(disk) = &self.cache.disk {
+            if disk.max_entries == 0 {
+                return invalid("cache.disk.max_entries", "must be greater than 0");
+            }
+            if disk.max_bytes == 0 {
+                return invalid("cache.disk.max_bytes", "must be greater than 0");
+            }
+            if disk.max_bytes < self.cache.max_body_size {
+                return invalid("cache.disk.max_bytes", "must be at least cache.max_body_size");
+            }
+        }
+        if self.upstream.connect_timeout.is_zero() {
+            return invalid("upstream.connect_timeout", "must be greater than 0");
+        }
+        if self.upstream.request_timeout.is_zero() {
+            return invalid("upstream.request_timeout", "must be greater than 0");
+        }
+        if self.access_log.path.as_ref().is_some_and(|path| path.file_name().is_none()) {
+            return invalid("access_log.path", "must name a file");
+        }
+        if let Some(admin) = &self.admin {
+            if admin.token.is_empty() {
+                return invalid("admin.token", "must not be empty");
+            }
+            if self.listen.contains(&admin.listen) {
+                return invalid("admin.listen", "must differ from the proxy's listen addresses");
+            }
+        }
+        if let Some(otlp) = &self.otlp {
+            if !otlp.endpoint.parse::<hyper::Uri>().is_ok_and(|uri| uri.scheme().is_some()) {
+                return invalid("otlp.endpoint", "must be an absolute URL");
+            }
+            if !(0.0..=1.0).contains(&otlp.sample_ratio) {
+                return invalid("otlp.sample_ratio", "must be between 0 and 1");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr<Err = String>,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The key of the rule `toml` breaks, if any.
+    fn invalid_key(toml: &str) -> Option<String> {
+        let config: Config = toml::from_str(toml).unwrap();
+        match config.validate() {
+            Ok(()) => None,
+            Err(ConfigError::Invalid { key, .. }) => Some(key),
+            Err(error) => panic!("unexpected error: {}", error),
+        }
+    }
+
+    fn assert_invalid(key: &str, toml: &str) {
+        assert_eq!(invalid_key(toml).as_deref(), Some(key), "{}", toml);
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(invalid_key(""), None);
+        assert_eq!(invalid_key(include_str!("../config.example.toml")), None);
+    }
+
+    #[test]
+    fn validates_listeners_and_routes() {
+        assert_invalid("listen", "listen = []");
+        let route = |fields: &str| format!("[[routes]]\n{}\n", fields);
+        assert_invalid("routes[0]", &route("name = \"\"\nprefix = \"/\"\nupstream = \"http://a\""));
+        assert_invalid("routes[0]", &route("name = \"a\"\nupstream = \"http://a\""));
+        assert_invalid("routes[0]", &route("name = \"a\"\nprefix = \"a\"\nupstream = \"http://a\""));
+        assert_invalid("routes[0]", &route("name = \"a\"\nprefix = \"/\"\nupstream = \"ftp://a\""));
+        assert_invalid("routes[0]", &route("name = \"a\"\nprefix = \"/\"\nupstream = \"/a\""));
+        assert_invalid("routes[0]", &route("name = \"a\"\nprefix = \"/\"\nupstream = \"http://a/?b\""));
+        let duplicate = route("name = \"a\"\nprefix = \"/a\"\nupstream = \"http://a\"").repeat(2);
+        assert_invalid("routes[1].name", &duplicate);
+        assert_invalid("allowed_origins", "origin_header = true");
+        assert_eq!(invalid_key("origin_header = true\nallowed_origins = [\"https://a\"]"), None);
+    }
+
+    #[test]
+    fn validates_cache_limits() {
+        assert_invalid("cache.max_entries", "[cache]\nmax_entries = 0");
+        assert_invalid("cache.max_bytes", "[cache]\nmax_bytes = 0");
+        assert_invalid("cache.shards", "[cache]\nshards = 0");
+        assert_invalid("cache.max_body_size", "[cache]\nmax_bytes = 1000\nshards = 2\nmax_body_size = 501");
+        assert_eq!(invalid_key("[cache]\nmax_bytes = 1000\nshards = 2\nmax_body_size = 500"), None);
+        assert_invalid("cache.disk.max_entries", "[cache.disk]\nmax_entries = 0");
+        assert_invalid("cache.disk.max_bytes", "[cache]\nmax_body_size = 0\n[cache.disk]\nmax_bytes = 0");
+        assert_invalid("cache.disk.max_bytes", "[cache]\nmax_body_size = 100\n[cache.disk]\nmax_bytes = 99");
+    }
+
+    #[test]
+    fn validates_timeouts_logging_admin_and_tracing() {
+        assert_invalid("upstream.connect_timeout", "[upstream]\nconnect_timeout = \"0s\"");
+        assert_invalid("upstream.request_timeout", "[upstream]\nrequest_timeout = \"0s\"");
+        assert_invalid("access_log.path", "[access_log]\npath = \"logs/..\"");
+        assert_invalid("admin.token", "[admin]\nlisten = \"127.0.0.1:3001\"");
+        assert_invalid("admin.listen", "[admin]\nlisten = \"127.0.0.1:3000\"\ntoken = \"secret\"");
+        assert_invalid("otlp.endpoint", "[otlp]\nendpoint = \"collector:4318\"");
+        assert_invalid("otlp.sample_ratio", "[otlp]\nsample_ratio = 1.5");
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = std::env::temp_dir().join(format!("proxy-with-cache-config-{}.toml", std::process::id()));
+        fs::write(&path, "listen = [\"127.0.0.1:4000\"]\n[cache]\ndefault_ttl = \"1m\"\nmax_entries = 5\n").unwrap();
+        let args = ["proxy-with-cache", "--config", path.to_str().unwrap(), "--default-ttl", "5s", "--listen", "127.0.0.1:5000"];
+        let config = Config::load(Cli::try_parse_from(args).unwrap()).unwrap();
+        assert_eq!(config.listen, vec![SocketAddr::from(([127, 0, 0, 1], 5000))]);
+        assert_eq!(config.cache.default_ttl, Duration::from_secs(5));
+        assert_eq!(config.cache.max_entries, 5);
+
+        let config = Config::load(Cli::try_parse_from(["proxy-with-cache", "-c", path.to_str().unwrap()]).unwrap()).unwrap();
+        assert_eq!(config.listen, vec![SocketAddr::from(([127, 0, 0, 1], 4000))]);
+        assert_eq!(config.cache.default_ttl, Duration::from_secs(60));
+        fs::remove_file(&path).unwrap();
+
+        // Flags are validated like the file.
+        assert!(Config::load(Cli::try_parse_from(["proxy-with-cache", "--max-entries", "0"]).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,1057 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::{stream, StreamExt};
+use hyper::{
+    body::{Bytes, HttpBody},
+    client::HttpConnector,
+    header,
+    http::{request, HeaderValue},
+    {Body, Request, Response, Client, StatusCode, Method, HeaderMap, Uri, Version},
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use sha2::{Digest, Sha256};
+use tracing::{field, Instrument};
+
+use crate::{
+    access::{self, DeniedAddress, FilteringResolver, Network},
+    cache_control::{self, CacheControl},
+    cache_key::{CacheKey, KeyBuilder},
+    cached_response::{self, CachedResponse, FreshnessDefaults, RESPONSE_IS_STALE, REVALIDATION_FAILED},
+    config::{Config, UpstreamConfig},
+    error::ProxyError,
+    flight::{Flight, FlightGuard, Flights, Role},
+    forwarding::{Forwarding, Peer},
+    logging::{self, AccessLog, LoggedBody},
+    metrics::{CacheResult, Metrics},
+    range::RangeRequest,
+    router::{Router, Target},
+    store::CacheStore,
+    telemetry::{self, Traced},
+    tls,
+};
+
+
+type UpstreamClient = Client<Traced<HttpsConnector<Traced<HttpConnector<FilteringResolver>>>>>;
+
+/// Marks requests whose upstream the client picked with the `Origin` header. Only these are
+/// kept away from the denied networks, routes are trusted to point where the operator wants.
+#[derive(Clone, Copy)]
+struct ClientChosenUpstream;
+
+enum RequestBody {
+    Buffered(Bytes),
+    /// What was read so far followed by the rest of the stream.
+    TooLarge(Body),
+}
+
+pub struct Controller {
+    client: UpstreamClient,
+    restricted_client: UpstreamClient,
+    store: Arc<dyn CacheStore>,
+    key_builder: Box<dyn KeyBuilder>,
+    route_key_builders: HashMap<String, Box<dyn KeyBuilder>>,
+    freshness: FreshnessDefaults,
+    max_body_size: usize,
+    cache_post: bool,
+    head_metadata: bool,
+    coalesce_timeout: Duration,
+    request_timeout: Duration,
+    router: Router,
+    denied_networks: Vec<Network>,
+    forwarding: Forwarding,
+    flights: Arc<Flights>,
+    metrics: Arc<Metrics>,
+}
+
+impl Controller {
+    pub fn new(
+        config: &Config,
+        store: Arc<dyn CacheStore>,
+        key_builder: Box<dyn KeyBuilder>,
+        metrics: Arc<Metrics>,
+    ) -> Controller {
+        Controller {
+            client: upstream_client(&config.upstream, Vec::new()),
+            restricted_client: upstream_client(&config.upstream, config.denied_networks.clone()),
+            store,
+            key_builder,
+            route_key_builders: config.routes
+                .iter()
+                .filter_map(|route| {
+                    let builder = route.key.clone()?;
+                    Some((route.name.clone(), Box::new(builder) as Box<dyn KeyBuilder>))
+                })
+                .collect(),
+            freshness: FreshnessDefaults {
+                ttl: config.cache.default_ttl,
+                stale_if_error: config.cache.stale_if_error,
+                stale_while_revalidate: config.cache.stale_while_revalidate,
+            },
+            max_body_size: config.cache.max_body_size,
+            cache_post: config.cache.cache_post,
+            head_metadata: config.cache.head_metadata,
+            coalesce_timeout: config.cache.coalesce_timeout,
+            request_timeout: config.upstream.request_timeout,
+            router: Router::new(config.routes.clone(), config.origin_header, config.allowed_origins.clone()),
+            denied_networks: config.denied_networks.clone(),
+            forwarding: Forwarding::new(&config.forwarding),
+            flights: Arc::new(Flights::default()),
+            metrics,
+        }
+    }
+
+    pub async fn process(self: Arc<Self>, mut req: Request<Body>, peer: Peer) -> Result<Response<LoggedBody>, Infallible> {
+        let start = Instant::now();
+        let access_log = AccessLog::new(peer.addr, &req);
+        let span = tracing::info_span!(
+            "request",
+            otel.kind = "server",
+            http.request.method = %req.method(),
+            url.full = %req.uri(),
+            http.response.status_code = field::Empty,
+        );
+        telemetry::set_parent(&span, req.headers());
+        req.extensions_mut().insert(peer);
+        // HTTP/2 requests carry the authority in the URI alone.
+        if let Some(authority) = req.uri().authority().filter(|_| !req.headers().contains_key(header::HOST)) {
+            if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                req.headers_mut().insert(header::HOST, host);
+            }
+        }
+        let (response, exchange) = logging::capture(self.clone().handle(req)).instrument(span.clone()).await;
+        let response = match response {
+            Ok(response) => response,
+            Err(error) => error.into_response(),
+        };
+        span.record("http.response.status_code", response.status().as_u16());
+        self.metrics.request(response.status(), start.elapsed());
+        Ok(access_log.attach(response, exchange, span))
+    }
+
+    async fn handle(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>, ProxyError> {
+        let (mut parts, body) = req.into_parts();
+        let target = self.router.route(&mut parts)?;
+        logging::record_upstream(&target.origin);
+        parts.uri = target.uri.clone();
+        if target.route.is_none() {
+            parts.extensions.insert(ClientChosenUpstream);
+        }
+        if !self.is_cacheable_method(&parts.method) {
+            self.cache_result(CacheResult::Bypass);
+            return self.forward(Request::from_parts(parts, body), &target).await;
+        }
+        // Ranges are cut from the full response, so the cache only ever holds complete bodies.
+        let range = match parts.method {
+            Method::GET => RangeRequest::take(&mut parts.headers),
+            _ => None,
+        };
+        let response = self.clone().serve(parts, body, &target).await?;
+        match range {
+            Some(range) => Ok(range.apply(response, self.max_body_size).await),
+            None => Ok(response),
+        }
+    }
+
+    async fn serve(self: Arc<Self>, parts: request::Parts, body: Body, target: &Target) -> Result<Response<Body>, ProxyError> {
+        let mut key = self.key_builder(target).build(&parts, &target.origin);
+        let request_cache_control = CacheControl::from_headers(&parts.headers);
+
+        // A stored GET response answers HEAD requests for the same URL.
+        if parts.method == Method::HEAD {
+            let now = SystemTime::now();
+            let get_key = CacheKey { method: Method::GET, ..key.clone() };
+            if let Some(mut cached) = self.lookup(&get_key, &parts.headers).await {
+                if cached.satisfies(&request_cache_control, now) {
+                    self.cache_result(self.mark_if_stale(&mut cached, now));
+                    return Ok(cached.to_response_with_body(Body::empty(), now));
+                }
+            }
+            if !self.head_metadata {
+                self.cache_result(CacheResult::Bypass);
+                return self.forward(Request::from_parts(parts, body), target).await;
+            }
+        }
+
+        let body = if parts.method == Method::POST {
+            match self.read_request_body(body, &parts.headers).await? {
+                RequestBody::Buffered(body) => {
+                    key.body_digest = Some(Sha256::digest(&body).into());
+                    Body::from(body)
+                },
+                RequestBody::TooLarge(body) => {
+                    self.cache_result(CacheResult::Bypass);
+                    return self.forward(Request::from_parts(parts, body), target).await;
+                },
+            }
+        } else {
+            body
+        };
+        let req = Request::from_parts(parts, body);
+
+        let now = SystemTime::now();
+        let stale = match self.lookup(&key, req.headers()).await {
+            Some(mut cached_response) if cached_response.satisfies(&request_cache_control, now) => {
+                self.cache_result(self.mark_if_stale(&mut cached_response, now));
+                return Ok(cached_response.to_response(now));
+            },
+            Some(cached_response)
+                if cached_response.has_validators()
+                    || cached_response.is_usable_if_error(now)
+                    || cached_response.is_usable_while_revalidating(now) =>
+            {
+                Some(cached_response)
+            },
+            _ => None
+        };
+
+        // Within stale-while-revalidate the stale copy is served right away, and only the
+        // flight's leader refreshes it. Clients asking for a validated response wait instead.
+        let wants_validation = request_cache_control.no_cache || request_cache_control.max_age.is_some();
+        if let Some(cached) = stale
+            .as_ref()
+            .filter(|cached| !wants_validation && cached.is_usable_while_revalidating(now))
+        {
+            let mut cached = cached.clone();
+            cached.mark_stale(RESPONSE_IS_STALE);
+            self.cache_result(CacheResult::Stale);
+            if let Role::Leader(flight) = self.flights.join(&key) {
+                let uri = req.uri().clone();
+                let controller = self.clone();
+                let refresh = async move {
+                    if let Err(error) = controller.fetch(flight, key, req, stale).await {
+                        tracing::warn!(%uri, %error, "background refresh failed");
+                    }
+                };
+                tokio::spawn(refresh.instrument(tracing::info_span!("cache.refresh")));
+            }
+            return Ok(cached.to_response(now));
+        }
+        if request_cache_control.only_if_cached {
+            self.cache_result(CacheResult::Miss);
+            return Err(ProxyError::NotCached);
+        }
+
+        let flight = match self.flights.join(&key) {
+            Role::Leader(flight) => flight,
+            Role::Follower(flight) => {
+                self.cache_result(CacheResult::Miss);
+                return self.follow(&flight, req).await;
+            },
+        };
+        let (response, result) = match self.clone().fetch(flight, key, req, stale).await {
+            Ok((response, result)) => (Ok(response), result),
+            Err(error) => (Err(error), CacheResult::Miss),
+        };
+        self.cache_result(result);
+        response
+    }
+
+    fn cache_result(&self, result: CacheResult) {
+        self.metrics.cache_result(result);
+        logging::record_cache_result(result);
+    }
+
+    fn mark_if_stale(&self, cached: &mut CachedResponse, now: SystemTime) -> CacheResult {
+        if cached.is_fresh(now) {
+            return CacheResult::Hit;
+        }
+        cached.mark_stale(RESPONSE_IS_STALE);
+        CacheResult::Stale
+    }
+
+    /// Requests `req` from the upstream as the flight's leader, revalidating `stale` if it is set.
+    /// Also returns how the cache was involved in the response.
+    async fn fetch(
+        self: Arc<Self>,
+        flight: FlightGuard,
+        key: CacheKey,
+        mut req: Request<Body>,
+        stale: Option<CachedResponse>,
+    ) -> Result<(Response<Body>, CacheResult), ProxyError> {
+        // A 304 only refreshes `stale` when it answers the stored validators rather than
+        // the client's own, which are passed on as they are otherwise.
+        let validating = stale.as_ref().is_some_and(CachedResponse::has_validators);
+        if let Some(stale) = stale.as_ref().filter(|_| validating) {
+            stale.add_validators(req.headers_mut());
+        }
+        let (method, uri) = (req.method().clone(), req.uri().clone());
+        let request_headers = req.headers().clone();
+        let request_time = SystemTime::now();
+        let response = match self.proxy(req).await {
+            Ok(response) if !is_server_error(response.status()) => response,
+            result => {
+                let now = SystemTime::now();
+                return match stale {
+                    Some(mut cached) if cached.is_usable_if_error(now) => {
+                        cached.mark_stale(REVALIDATION_FAILED);
+                        flight.complete_with(cached.body().clone());
+                        flight.publish(Some(cached.clone()));
+                        Ok((cached.to_response(now), CacheResult::Stale))
+                    },
+                    _ => result.map(|response| (response, CacheResult::Miss)),
+                };
+            },
+        };
+        tracing::debug!(%uri, status = %response.status(), headers = ?response.headers(), "upstream response");
+        let response_time = SystemTime::now();
+        // With `cache_post` on, POSTs get here too. Their responses are cached like a query's,
+        // but one that reached the upstream may still have changed what the other methods of
+        // its URL return. Its own response is stored after this.
+        let status = response.status();
+        if method == Method::POST && (status.is_success() || status.is_redirection()) {
+            if let Err(error) = self.store.invalidate(&key).await {
+                tracing::warn!(%error, "failed to invalidate cached responses");
+            }
+        }
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return match stale.filter(|_| validating) {
+                Some(mut cached) => {
+                    cached.merge_not_modified(response.headers());
+                    cached.update_freshness(request_time, response_time, self.freshness);
+                    flight.complete_with(cached.body().clone());
+                    flight.publish(Some(cached.clone()));
+                    let response = cached.to_response(response_time);
+                    if cache_control::is_storable(&request_headers, cached.status(), cached.headers()) {
+                        self.store(key, cached).await;
+                    }
+                    Ok((response, CacheResult::Revalidated))
+                },
+                None => Ok((response, CacheResult::Miss))
+            };
+        }
+
+        let (parts, upstream) = response.into_parts();
+        // HEAD responses state the length of a body they don't have.
+        let too_large = method != Method::HEAD
+            && content_length(&parts.headers).is_some_and(|length| length > self.max_body_size);
+        if !cache_control::is_storable(&request_headers, parts.status, &parts.headers)
+            || cached_response::varies_on_everything(&parts.headers)
+            || too_large
+        {
+            return Ok((Response::from_parts(parts, upstream), CacheResult::Miss));
+        }
+
+        let mut cached = CachedResponse::new(&request_headers, &parts, request_time, response_time, self.freshness);
+        // Followers can't share a response that has to be revalidated for every request.
+        flight.publish(Some(cached.clone()).filter(|cached| cached.is_fresh(response_time)));
+        let body = flight.subscribe().unwrap_or_default();
+        let controller = self.clone();
+        let fill = async move {
+            // The flight keeps accepting followers until the body is in the cache.
+            if let Some(body) = flight.distribute(upstream, controller.max_body_size).await {
+                cached.set_body(body);
+                controller.store(key, cached).await;
+            }
+            drop(flight);
+        };
+        tokio::spawn(fill.instrument(tracing::info_span!("cache.fill")));
+        Ok((Response::from_parts(parts, body), CacheResult::Miss))
+    }
+
+    async fn follow(&self, flight: &Arc<Flight>, req: Request<Body>) -> Result<Response<Body>, ProxyError> {
+        if let Some(head) = flight.wait(self.coalesce_timeout).await {
+            let now = SystemTime::now();
+            if head.matches_vary(req.headers()) {
+                if let Some(body) = flight.subscribe() {
+                    return Ok(head.to_response_with_body(body, now));
+                }
+            }
+        }
+        self.proxy(req).await
+    }
+
+    fn is_cacheable_method(&self, method: &Method) -> bool {
+        *method == Method::GET || *method == Method::HEAD || (self.cache_post && *method == Method::POST)
+    }
+
+    /// Passes a request that is never answered from the cache straight to the upstream.
+    async fn forward(&self, req: Request<Body>, target: &Target) -> Result<Response<Body>, ProxyError> {
+        let method = req.method().clone();
+        let uri = req.uri().clone();
+        let response = self.proxy(req).await?;
+        self.invalidate(&method, &uri, target, &response).await;
+        Ok(response)
+    }
+
+    // RFC 9111 section 4.4: a successful unsafe request invalidates its target URI and the
+    // same-origin URIs in Location and Content-Location.
+    async fn invalidate(&self, method: &Method, uri: &Uri, target: &Target, response: &Response<Body>) {
+        let status = response.status();
+        if method.is_safe() || !(status.is_success() || status.is_redirection()) {
+            return;
+        }
+        let mut uris = vec![uri.clone()];
+        for name in [header::LOCATION, header::CONTENT_LOCATION] {
+            if let Some(uri) = response.headers().get(name).and_then(|value| same_origin_reference(uri, value)) {
+                uris.push(uri);
+            }
+        }
+        for uri in uris {
+            let (parts, _) = Request::get(uri).body(()).unwrap().into_parts();
+            let key = self.key_builder(target).build(&parts, &target.origin);
+            if let Err(error) = self.store.invalidate(&key).await {
+                tracing::warn!(%error, "failed to invalidate cached responses");
+            }
+        }
+    }
+
+    fn key_builder(&self, target: &Target) -> &dyn KeyBuilder {
+        target.route
+            .as_ref()
+            .and_then(|route| self.route_key_builders.get(route))
+            .unwrap_or(&self.key_builder)
+            .as_ref()
+    }
+
+    async fn lookup(&self, key: &CacheKey, request_headers: &HeaderMap<HeaderValue>) -> Option<CachedResponse> {
+        let span = tracing::info_span!("cache.lookup", hit = field::Empty);
+        let cached = self.store.get(key, request_headers).instrument(span.clone()).await;
+        span.record("hit", cached.is_some());
+        cached
+    }
+
+    async fn store(&self, key: CacheKey, cached: CachedResponse) {
+        if let Err(error) = self.store.put(key, cached).await {
+            tracing::warn!(%error, "failed to store a response");
+        }
+    }
+
+    async fn proxy(&self, mut req: Request<Body>) -> Result<Response<Body>, ProxyError> {
+        let origin = format!(
+            "{}://{}",
+            req.uri().scheme_str().unwrap_or("http"),
+            req.uri().authority().map_or("", |authority| authority.as_str())
+        );
+        let restricted = req.extensions().get::<ClientChosenUpstream>().is_some();
+        // Literal addresses never reach the resolver, so they are checked here.
+        let host = req.uri().host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+        if let Ok(address) = host.parse::<IpAddr>() {
+            if restricted && access::is_denied(&self.denied_networks, address) {
+                return Err(ProxyError::Forbidden { origin, reason: DeniedAddress(address).to_string() });
+            }
+        }
+
+        let host = host.to_owned();
+        let span = tracing::info_span!(
+            "upstream",
+            otel.kind = "client",
+            http.request.method = %req.method(),
+            url.full = %req.uri(),
+            http.response.status_code = field::Empty,
+        );
+        let peer = req.extensions().get::<Peer>().copied();
+        let (version, uri) = (req.version(), req.uri().clone());
+        self.forwarding.request(req.headers_mut(), version, &uri, peer);
+        // The connector picks HTTP/2 for upstreams that offer it, whatever the client spoke.
+        *req.version_mut() = Version::HTTP_11;
+        telemetry::inject(&span, req.headers_mut());
+        let start = Instant::now();
+        let client = if restricted { &self.restricted_client } else { &self.client };
+        let request = tokio::time::timeout(self.request_timeout, client.request(req));
+        let result = match request.instrument(span.clone()).await {
+            Ok(Ok(mut response)) => {
+                let version = response.version();
+                self.forwarding.response(response.headers_mut(), version);
+                *response.version_mut() = Version::default();
+                Ok(response)
+            },
+            Ok(Err(error)) => Err(ProxyError::from_upstream(error, &origin)),
+            Err(_) => Err(ProxyError::UpstreamTimeout),
+        };
+        let status = result.as_ref().ok().map(Response::status);
+        if let Some(status) = status {
+            span.record("http.response.status_code", status.as_u16());
+        }
+        self.metrics.upstream_request(&host, status, start.elapsed());
+        logging::record_upstream_time(start.elapsed());
+        result
+    }
+
+    /// Buffers a request body to key the cache on, unless it turns out larger than
+    /// `max_body_size`.
+    async fn read_request_body(&self, mut body: Body, headers: &HeaderMap<HeaderValue>) -> Result<RequestBody, ProxyError> {
+        if content_length(headers).is_some_and(|length| length > self.max_body_size) {
+            return Ok(RequestBody::TooLarge(body));
+        }
+        let mut chunks = Vec::new();
+        let mut size = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|error| ProxyError::BadRequestBody(error.to_string()))?;
+            size += chunk.len();
+            chunks.push(chunk);
+            if size > self.max_body_size {
+                let read = stream::iter(chunks.into_iter().map(Ok::<_, hyper::Error>));
+                return Ok(RequestBody::TooLarge(Body::wrap_stream(read.chain(body))));
+            }
+        }
+        Ok(RequestBody::Buffered(Bytes::from(chunks.concat())))
+    }
+
+    pub async fn clear_expired_cache(&self) {
+        let mut last_stats = Vec::new();
+        loop {
+            if let Err(error) = self.store.purge(SystemTime::now()).await {
+                tracing::warn!(%error, "failed to purge expired responses");
+            }
+            let stats = self.store.stats().await;
+            if last_stats != stats {
+                for (tier, stats) in &stats {
+                    tracing::info!(
+                        tier,
+                        entries = stats.entries,
+                        bytes = stats.bytes,
+                        evictions = stats.evictions,
+                        rejected = stats.rejections,
+                        "cache size"
+                    );
+                }
+                last_stats = stats;
+            }
+            tokio::time::sleep(Duration::new(1, 0)).await;
+        }
+    }
+}
+
+// The statuses RFC 5861 section 4 counts as errors, alongside failing to reach the upstream.
+fn is_server_error(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::INTERNAL_SERVER_ERROR | StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+pub(crate) fn upstream_client(config: &UpstreamConfig, denied_networks: Vec<Network>) -> UpstreamClient {
+    let mut http = HttpConnector::new_with_resolver(FilteringResolver::new(denied_networks));
+    http.enforce_http(false);
+    http.set_connect_timeout(Some(config.connect_timeout));
+    let https = HttpsConnectorBuilder::new()
+        .with_tls_config(tls::client_config())
+        .https_or_http()
+        .enable_all_versions()
+        .wrap_connector(Traced::new(http, "tcp connect"));
+    Client::builder().build(Traced::new(https, "connect"))
+}
+
+fn same_origin_reference(base: &Uri, value: &HeaderValue) -> Option<Uri> {
+    let uri: Uri = value.to_str().ok()?.parse().ok()?;
+    let same_origin = match uri.authority() {
+        Some(authority) => uri.scheme() == base.scheme() && Some(authority) == base.authority(),
+        None => uri.path().starts_with('/'),
+    };
+    same_origin.then_some(uri)
+}
+
+fn content_length(headers: &HeaderMap<HeaderValue>) -> Option<usize> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    use async_trait::async_trait;
+    use futures::future;
+    use hyper::{
+        server::{conn::AddrStream, Server},
+        service::{make_service_fn, service_fn},
+    };
+
+    use super::*;
+    use crate::{
+        cache::{CacheStats, EntryInfo},
+        cache_key::DefaultKeyBuilder,
+        router::Route,
+    };
+
+    /// Keeps one response per key and records which methods the controller called.
+    #[derive(Default)]
+    struct MockStore {
+        entries: Mutex<HashMap<CacheKey, CachedResponse>>,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl MockStore {
+        fn record(&self, call: &str, key: &CacheKey) {
+            self.calls.lock().unwrap().push(format!("{} {} {}", call, key.method, key.url()));
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl CacheStore for MockStore {
+        async fn get(&self, key: &CacheKey, _: &HeaderMap<HeaderValue>) -> Option<CachedResponse> {
+            self.record("get", key);
+            self.entries.lock().unwrap().get(key).cloned()
+        }
+
+        async fn put(&self, key: CacheKey, cached: CachedResponse) -> io::Result<()> {
+            self.record("put", &key);
+            self.entries.lock().unwrap().insert(key, cached);
+            Ok(())
+        }
+
+        async fn delete(&self, key: &CacheKey) -> io::Result<()> {
+            self.record("delete", key);
+            self.entries.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        async fn invalidate(&self, target: &CacheKey) -> io::Result<()> {
+            self.record("invalidate", target);
+            self.entries.lock().unwrap().retain(|key, _| !key.same_url(target));
+            Ok(())
+        }
+
+        async fn keys(&self) -> io::Result<Vec<CacheKey>> {
+            Ok(self.entries.lock().unwrap().keys().cloned().collect())
+        }
+
+        async fn entries(&self, _: SystemTime) -> io::Result<Vec<EntryInfo>> {
+            Ok(Vec::new())
+        }
+
+        async fn purge(&self, _: SystemTime) -> io::Result<()> {
+            Ok(())
+        }
+
+        async fn stats(&self) -> Vec<(&'static str, CacheStats)> {
+            Vec::new()
+        }
+    }
+
+    fn route(name: &str, prefix: Option<&str>, upstream: &str) -> Route {
+        Route {
+            name: name.to_owned(),
+            host: None,
+            prefix: prefix.map(str::to_owned),
+            upstream: upstream.to_owned(),
+            strip_prefix: false,
+            key: None,
+        }
+    }
+
+    fn config(upstream: &str) -> Config {
+        Config {
+            routes: vec![route("upstream", None, upstream)],
+            ..Config::default()
+        }
+    }
+
+    fn controller(upstream: &str, store: Arc<MockStore>) -> Arc<Controller> {
+        controller_with(&config(upstream), store)
+    }
+
+    fn controller_with(config: &Config, store: Arc<MockStore>) -> Arc<Controller> {
+        Arc::new(Controller::new(config, store, Box::new(DefaultKeyBuilder::default()), Arc::new(Metrics::default())))
+    }
+
+    /// An upstream answering every request with `respond`.
+    fn serve_upstream<F>(respond: F) -> String
+    where
+        F: Fn(Request<Body>) -> Response<Body> + Clone + Send + Sync + 'static,
+    {
+        let make_svc = make_service_fn(move |_| {
+            let respond = respond.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| future::ready(Ok::<_, Infallible>(respond(req)))))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        format!("http://{}", addr)
+    }
+
+    /// An upstream answering every request with a cacheable body, counting the requests.
+    fn start_upstream(requests: Arc<AtomicUsize>) -> String {
+        serve_upstream(move |req| {
+            let count = requests.fetch_add(1, Ordering::SeqCst) + 1;
+            let body = format!("{} {}", req.method(), count);
+            Response::builder().header("cache-control", "max-age=60").body(Body::from(body)).unwrap()
+        })
+    }
+
+    /// Puts a response for `path` with `headers` into the store, as if it had been fetched now.
+    fn store_response(store: &MockStore, upstream: &str, path: &str, headers: &[(&str, &str)], body: &'static str) {
+        let (parts, _) = Request::get(format!("{}{}", upstream, path)).body(()).unwrap().into_parts();
+        let key = DefaultKeyBuilder::default().build(&parts, upstream);
+        let mut response = Response::builder();
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        let (response, _) = response.body(()).unwrap().into_parts();
+        let now = SystemTime::now();
+        let defaults = FreshnessDefaults {
+            ttl: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+        };
+        let mut cached = CachedResponse::new(&HeaderMap::new(), &response, now, now, defaults);
+        cached.set_body(Bytes::from_static(body.as_bytes()));
+        store.entries.lock().unwrap().insert(key, cached);
+    }
+
+    async fn send_request(controller: &Arc<Controller>, req: Request<Body>) -> Response<Bytes> {
+        let peer = Peer { addr: SocketAddr::from(([127, 0, 0, 1], 40000)), tls: false };
+        let (parts, body) = controller.clone().process(req, peer).await.unwrap().into_parts();
+        Response::from_parts(parts, hyper::body::to_bytes(body).await.unwrap())
+    }
+
+    async fn send(controller: &Arc<Controller>, method: Method, path: &str) -> (StatusCode, Bytes) {
+        let req = Request::builder().method(method).uri(path).body(Body::empty()).unwrap();
+        let response = send_request(controller, req).await;
+        (response.status(), response.into_body())
+    }
+
+    // The cache fill runs in the background once the response body has been read.
+    async fn wait_for_put(store: &MockStore) {
+        wait_for_call(store, "put").await;
+    }
+
+    async fn wait_for_call(store: &MockStore, prefix: &str) {
+        for _ in 0..100 {
+            if store.calls().iter().any(|call| call.starts_with(prefix)) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no `{}` call: {:?}", prefix, store.calls());
+    }
+
+    #[tokio::test]
+    async fn serves_hits_from_the_store_without_the_upstream() {
+        // Nothing listens here, so any upstream request would fail.
+        let upstream = "http://127.0.0.1:9";
+        let store = Arc::new(MockStore::default());
+        store_response(&store, upstream, "/cached", &[("cache-control", "max-age=60")], "from the store");
+
+        let controller = controller(upstream, store.clone());
+        assert_eq!(send(&controller, Method::GET, "/cached").await, (StatusCode::OK, Bytes::from("from the store")));
+        assert_eq!(store.calls(), vec![format!("get GET {}/cached", upstream)]);
+    }
+
+    #[tokio::test]
+    async fn stores_misses_and_invalidates_after_unsafe_requests() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let upstream = start_upstream(requests.clone());
+        let store = Arc::new(MockStore::default());
+        let controller = controller(&upstream, store.clone());
+
+        assert_eq!(send(&controller, Method::GET, "/a").await, (StatusCode::OK, Bytes::from("GET 1")));
+        wait_for_put(&store).await;
+        assert_eq!(send(&controller, Method::GET, "/a").await, (StatusCode::OK, Bytes::from("GET 1")));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        assert_eq!(send(&controller, Method::PUT, "/a").await, (StatusCode::OK, Bytes::from("PUT 2")));
+        assert!(store.calls().contains(&format!("invalidate GET {}/a", upstream)));
+        assert!(store.entries.lock().unwrap().is_empty());
+
+        assert_eq!(send(&controller, Method::GET, "/a").await, (StatusCode::OK, Bytes::from("GET 3")));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn streams_bodies_while_storing_them() {
+        let (chunks, receiver) = tokio::sync::mpsc::unbounded_channel::<&'static str>();
+        let receiver = Arc::new(Mutex::new(Some(receiver)));
+        let upstream = serve_upstream(move |_| {
+            let body = match receiver.lock().unwrap().take() {
+                Some(receiver) => Body::wrap_stream(stream::unfold(receiver, |mut receiver| async move {
+                    let chunk = receiver.recv().await?;
+                    Some((Ok::<_, io::Error>(chunk), receiver))
+                })),
+                None => Body::from("0123456789abcdef"),
+            };
+            Response::builder().header("cache-control", "max-age=60").body(body).unwrap()
+        });
+        let store = Arc::new(MockStore::default());
+        let mut config = config(&upstream);
+        config.cache.max_body_size = 12;
+        let controller = controller_with(&config, store.clone());
+        let peer = Peer { addr: SocketAddr::from(([127, 0, 0, 1], 40000)), tls: false };
+
+        // The client gets each chunk as soon as the upstream sends it.
+        chunks.send("first ").unwrap();
+        let req = Request::get("/stream").body(Body::empty()).unwrap();
+        let mut body = controller.clone().process(req, peer).await.unwrap().into_body();
+        assert_eq!(body.data().await.unwrap().unwrap(), "first ");
+        assert!(store.calls().iter().all(|call| !call.starts_with("put")));
+        chunks.send("second").unwrap();
+        drop(chunks);
+        assert_eq!(body.data().await.unwrap().unwrap(), "second");
+        assert!(body.data().await.is_none());
+        wait_for_put(&store).await;
+        assert_eq!(send(&controller, Method::GET, "/stream").await, (StatusCode::OK, Bytes::from("first second")));
+
+        // Past max_body_size the client still gets the whole body, only the cache write is dropped.
+        assert_eq!(send(&controller, Method::GET, "/big").await, (StatusCode::OK, Bytes::from("0123456789abcdef")));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!store.calls().iter().any(|call| call.starts_with("put") && call.ends_with("/big")));
+    }
+
+    #[tokio::test]
+    async fn serves_h2c_and_http1_clients_from_the_same_cache() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let upstream = start_upstream(requests.clone());
+        let store = Arc::new(MockStore::default());
+        let controller = controller(&upstream, store.clone());
+        let make_svc = make_service_fn(move |conn: &AddrStream| {
+            let peer = Peer { addr: conn.remote_addr(), tls: false };
+            let controller = controller.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| controller.clone().process(req, peer))) }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let url: Uri = format!("http://{}/a", server.local_addr()).parse().unwrap();
+        tokio::spawn(server);
+
+        // HTTP/2 with prior knowledge, then HTTP/1.1, each answered in its own version.
+        let h2c = Client::builder().http2_only(true).build_http::<Body>();
+        let http1 = Client::new();
+        for (client, version) in [(&h2c, Version::HTTP_2), (&http1, Version::HTTP_11), (&h2c, Version::HTTP_2)] {
+            let response = client.get(url.clone()).await.unwrap();
+            assert_eq!(response.version(), version);
+            assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "GET 1");
+            wait_for_put(&store).await;
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn keys_requests_with_the_matched_routes_builder() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let upstream = start_upstream(requests.clone());
+        let store = Arc::new(MockStore::default());
+        let mut config = config(&upstream);
+        let mut sorted = route("sorted", Some("/sorted"), &upstream);
+        sorted.key = Some(DefaultKeyBuilder { sort_query: true, ..DefaultKeyBuilder::default() });
+        config.routes.push(sorted);
+        let controller = controller_with(&config, store.clone());
+
+        assert_eq!(send(&controller, Method::GET, "/sorted?b=2&a=1").await.1, "GET 1");
+        wait_for_put(&store).await;
+        assert_eq!(send(&controller, Method::GET, "/sorted?a=1&b=2").await.1, "GET 1");
+        assert!(store.calls().contains(&format!("get GET {}/sorted?a=1&b=2", upstream)));
+
+        // Other routes keep the query order.
+        assert_eq!(send(&controller, Method::GET, "/plain?b=2&a=1").await.1, "GET 2");
+        assert_eq!(send(&controller, Method::GET, "/plain?a=1&b=2").await.1, "GET 3");
+    }
+
+    /// Answers `If-None-Match: "v1"` with a 304 and everything else with a new body.
+    fn revalidating_upstream() -> String {
+        serve_upstream(|req| {
+            let response = Response::builder().header("cache-control", "max-age=60");
+            match req.headers().get(header::IF_NONE_MATCH) {
+                Some(etag) if etag == "\"v1\"" => response.status(StatusCode::NOT_MODIFIED).body(Body::empty()),
+                Some(_) => response.status(StatusCode::NOT_MODIFIED).header("etag", "\"client\"").body(Body::empty()),
+                None => response.body(Body::from("new")),
+            }
+            .unwrap()
+        })
+    }
+
+    fn conditional_get(path: &str, etag: &'static str) -> Request<Body> {
+        Request::get(path).header(header::IF_NONE_MATCH, etag).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn revalidates_stale_entries_with_their_own_validators() {
+        let upstream = revalidating_upstream();
+        let store = Arc::new(MockStore::default());
+        store_response(&store, &upstream, "/a", &[("cache-control", "max-age=0"), ("etag", "\"v1\"")], "stored");
+        let controller = controller(&upstream, store.clone());
+
+        // The client's validator is replaced by the stored one, so the 304 refreshes the entry.
+        let response = send_request(&controller, conditional_get("/a", "\"client\"")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "stored");
+        assert!(store.calls().contains(&format!("put GET {}/a", upstream)));
+        let stored = store.entries.lock().unwrap().values().next().cloned().unwrap();
+        assert!(stored.is_fresh(SystemTime::now()));
+    }
+
+    #[tokio::test]
+    async fn passes_on_304s_for_the_clients_own_validators() {
+        let upstream = revalidating_upstream();
+        let store = Arc::new(MockStore::default());
+        // Kept past expiry for stale-if-error, but without validators to revalidate it with.
+        store_response(&store, &upstream, "/a", &[("cache-control", "max-age=0, stale-if-error=60")], "stored");
+        let controller = controller(&upstream, store.clone());
+
+        let response = send_request(&controller, conditional_get("/a", "\"client\"")).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()["etag"], "\"client\"");
+        assert!(response.body().is_empty());
+        assert!(!store.calls().iter().any(|call| call.starts_with("put")));
+    }
+
+    #[tokio::test]
+    async fn keeps_only_client_chosen_upstreams_from_denied_networks() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let upstream = start_upstream(requests.clone());
+        let mut config = config(&upstream);
+        config.routes[0].prefix = Some("/routed".to_owned());
+        config.origin_header = true;
+        config.allowed_origins = vec!["http://127.0.0.1:*".parse().unwrap()];
+        let controller = controller_with(&config, Arc::new(MockStore::default()));
+
+        assert_eq!(send(&controller, Method::GET, "/routed").await.0, StatusCode::OK);
+        let req = Request::get("/chosen").header(header::ORIGIN, &upstream).body(Body::empty()).unwrap();
+        assert_eq!(send_request(&controller, req).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_head_requests_from_stored_get_responses() {
+        let upstream = "http://127.0.0.1:9";
+        let store = Arc::new(MockStore::default());
+        store_response(&store, upstream, "/a", &[("cache-control", "max-age=60"), ("content-length", "6")], "stored");
+        let controller = controller(upstream, store.clone());
+
+        let response = send_request(&controller, Request::head("/a").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "6");
+        assert!(response.body().is_empty());
+        assert_eq!(store.calls(), vec![format!("get GET {}/a", upstream)]);
+    }
+
+    #[tokio::test]
+    async fn stores_head_metadata_for_bodies_too_large_to_cache() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let upstream = serve_upstream({
+            let requests = requests.clone();
+            move |_| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                Response::builder()
+                    .header("cache-control", "max-age=60")
+                    .header("content-length", "1000")
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        });
+        let store = Arc::new(MockStore::default());
+        let mut config = config(&upstream);
+        config.cache.head_metadata = true;
+        config.cache.max_body_size = 100;
+        let controller = controller_with(&config, store.clone());
+
+        for _ in 0..2 {
+            let response = send_request(&controller, Request::head("/big").body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_LENGTH], "1000");
+            wait_for_put(&store).await;
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn cached_posts_invalidate_the_other_methods_of_their_url() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let upstream = start_upstream(requests.clone());
+        let store = Arc::new(MockStore::default());
+        let mut config = config(&upstream);
+        config.cache.cache_post = true;
+        let controller = controller_with(&config, store.clone());
+        let post = || Request::post("/a").body(Body::from("query")).unwrap();
+
+        assert_eq!(send(&controller, Method::GET, "/a").await.1, "GET 1");
+        wait_for_put(&store).await;
+        assert_eq!(send_request(&controller, post()).await.body(), "POST 2");
+        wait_for_call(&store, "put POST").await;
+        assert!(store.calls().contains(&format!("invalidate POST {}/a", upstream)));
+
+        assert_eq!(send(&controller, Method::GET, "/a").await.1, "GET 3");
+        // The POST's own response is kept.
+        assert_eq!(send_request(&controller, post()).await.body(), "POST 2");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn only_if_cached_requests_never_reach_the_upstream() {
+        let upstream = "http://127.0.0.1:9";
+        let store = Arc::new(MockStore::default());
+        store_response(&store, upstream, "/cached", &[("cache-control", "max-age=60")], "stored");
+        store_response(&store, upstream, "/stale", &[("cache-control", "max-age=0")], "stale");
+        let controller = controller(upstream, store.clone());
+        let only_if_cached = |path: &str, cache_control: &'static str| {
+            Request::get(path).header(header::CACHE_CONTROL, cache_control).body(Body::empty()).unwrap()
+        };
+
+        let response = send_request(&controller, only_if_cached("/cached", "only-if-cached")).await;
+        assert_eq!((response.status(), response.body().as_ref()), (StatusCode::OK, b"stored".as_ref()));
+        let response = send_request(&controller, only_if_cached("/stale", "only-if-cached, max-stale")).await;
+        assert_eq!((response.status(), response.body().as_ref()), (StatusCode::OK, b"stale".as_ref()));
+        assert!(response.headers()[header::WARNING].to_str().unwrap().starts_with("110"));
+        for path in ["/stale", "/missing"] {
+            let response = send_request(&controller, only_if_cached(path, "only-if-cached")).await;
+            assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_stale_responses_when_the_upstream_fails() {
+        let failing = serve_upstream(|_| {
+            Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::from("down")).unwrap()
+        });
+        // Nothing listens on the second one.
+        for upstream in [failing.as_str(), "http://127.0.0.1:9"] {
+            let store = Arc::new(MockStore::default());
+            store_response(&store, upstream, "/a", &[("cache-control", "max-age=0, stale-if-error=60")], "stored");
+            store_response(&store, upstream, "/b", &[("cache-control", "max-age=0")], "stored");
+            let controller = controller(upstream, store);
+
+            let response = send_request(&controller, Request::get("/a").body(Body::empty()).unwrap()).await;
+            assert_eq!((response.status(), response.body().as_ref()), (StatusCode::OK, b"stored".as_ref()));
+            assert!(response.headers()[header::WARNING].to_str().unwrap().starts_with("111"));
+            // Without a stale-if-error window the failure is passed on.
+            let (status, _) = send(&controller, Method::GET, "/b").await;
+            assert!(status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::BAD_GATEWAY);
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_stale_responses_once_in_the_background() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let upstream = start_upstream(requests.clone());
+        let store = Arc::new(MockStore::default());
+        store_response(&store, &upstream, "/a", &[("cache-control", "max-age=0, stale-while-revalidate=60")], "stale");
+        let controller = controller(&upstream, store.clone());
+
+        let responses = future::join_all((0..5).map(|_| {
+            send_request(&controller, Request::get("/a").body(Body::empty()).unwrap())
+        }))
+        .await;
+        for response in responses {
+            assert_eq!((response.status(), response.body().as_ref()), (StatusCode::OK, b"stale".as_ref()));
+            assert!(response.headers()[header::WARNING].to_str().unwrap().starts_with("110"));
+        }
+        wait_for_put(&store).await;
+        assert_eq!(send(&controller, Method::GET, "/a").await.1, "GET 1");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Clients that ask for a validated response wait for it instead.
+        store_response(&store, &upstream, "/a", &[("cache-control", "max-age=0, stale-while-revalidate=60")], "stale");
+        let no_cache = Request::get("/a").header(header::CACHE_CONTROL, "no-cache").body(Body::empty()).unwrap();
+        assert_eq!(send_request(&controller, no_cache).await.body(), "GET 2");
+    }
+}
@@ -0,0 +1,703 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use hyper::{body::Bytes, header::HeaderName, http::HeaderValue, HeaderMap, Method};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::{CacheStats, EntryInfo, Expiries, Expiry, VariantInfo},
+    cache_key::{CacheKey, UrlIndex},
+    cached_response::{CachedResponse, Metadata},
+    eviction::EvictionPolicy,
+};
+
+
+const INDEX_FILE: &str = "index.json";
+const JOURNAL_FILE: &str = "journal.jsonl";
+const BODIES_DIR: &str = "bodies";
+// The journal is folded into the index once it has more records than this or the index.
+const MIN_JOURNAL_RECORDS: usize = 1000;
+
+
+/// The second cache tier: bodies are files named after their SHA-256 digest, shared by
+/// every variant with the same content, and the metadata lives in an index that is kept in
+/// memory. `flush` appends changed entries to a journal, and rewrites the index file instead
+/// once the journal has grown larger than it. All methods block on file I/O.
+pub struct DiskCache {
+    dir: PathBuf,
+    index: Mutex<Index>,
+    // Held while writing, so that an older snapshot never replaces a newer one.
+    flushing: Mutex<()>,
+    temp_files: AtomicU64,
+}
+
+struct Index {
+    bodies_dir: PathBuf,
+    entries: HashMap<CacheKey, Entry>,
+    expiries: Expiries,
+    urls: UrlIndex,
+    bodies: HashMap<String, BodyFile>,
+    // Served from this process only, not written to the index.
+    hits: HashMap<CacheKey, u64>,
+    policy: Box<dyn EvictionPolicy>,
+    max_entries: usize,
+    max_bytes: usize,
+    bytes: usize,
+    evictions: u64,
+    rejections: u64,
+    // Keys to record in the journal on the next flush.
+    changed: HashSet<CacheKey>,
+    // Records in the journal, and whether the next flush should rewrite the index instead.
+    journaled: usize,
+    compact: bool,
+}
+
+struct Entry {
+    variants: Vec<Variant>,
+    expiry: Expiry,
+}
+
+#[derive(Clone)]
+struct Variant {
+    response: CachedResponse,
+    digest: String,
+}
+
+struct BodyFile {
+    size: usize,
+    references: usize,
+}
+
+/// A key with its variants, in the index file and as a journal line. Journal lines without
+/// variants record a removal.
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    key: KeyRecord,
+    variants: Vec<VariantRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyRecord {
+    method: String,
+    origin: String,
+    path: String,
+    query: Option<String>,
+    headers: Vec<(String, Option<String>)>,
+    // Hex SHA-256 of the request body.
+    #[serde(default)]
+    body_sha256: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct VariantRecord {
+    metadata: Metadata,
+    digest: String,
+    size: usize,
+}
+
+impl DiskCache {
+    /// Loads the index in `dir` and replays the journal over it, keeping only entries whose body
+    /// file is intact, and deletes body files nothing refers to. An index that can't be parsed
+    /// is an error, and nothing is deleted.
+    pub fn open(
+        dir: &Path,
+        max_entries: usize,
+        max_bytes: usize,
+        policy: Box<dyn EvictionPolicy>,
+    ) -> io::Result<DiskCache> {
+        let bodies_dir = dir.join(BODIES_DIR);
+        fs::create_dir_all(&bodies_dir)?;
+        let records: Vec<IndexEntry> = match fs::read(dir.join(INDEX_FILE)) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|error| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("unreadable {}: {}", INDEX_FILE, error))
+            })?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error),
+        };
+        let journal: Vec<IndexEntry> = match fs::read(dir.join(JOURNAL_FILE)) {
+            // A line cut short by a crash is skipped.
+            Ok(contents) => contents
+                .split(|&byte| byte == b'\n')
+                .filter_map(|line| serde_json::from_slice(line).ok())
+                .collect(),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error),
+        };
+        let replayed = !journal.is_empty();
+        let mut loaded: Vec<(CacheKey, Vec<VariantRecord>)> = Vec::new();
+        let mut positions = HashMap::new();
+        for record in records.into_iter().chain(journal) {
+            let key = match record.key.into_key() {
+                Some(key) => key,
+                None => continue,
+            };
+            match positions.get(&key) {
+                Some(&position) => loaded[position] = (key, record.variants),
+                None => {
+                    positions.insert(key.clone(), loaded.len());
+                    loaded.push((key, record.variants));
+                },
+            }
+        }
+        loaded.retain(|(_, variants)| !variants.is_empty());
+
+        let mut index = Index {
+            bodies_dir,
+            entries: HashMap::new(),
+            expiries: Expiries::default(),
+            urls: UrlIndex::default(),
+            bodies: HashMap::new(),
+            hits: HashMap::new(),
+            policy,
+            max_entries,
+            max_bytes,
+            bytes: 0,
+            evictions: 0,
+            rejections: 0,
+            changed: HashSet::new(),
+            journaled: 0,
+            compact: false,
+        };
+        let mut verified = HashSet::new();
+        let mut dropped = false;
+        let loaded_keys = loaded.len();
+        for (key, variants) in loaded {
+            for variant in variants {
+                let intact = verified.contains(&variant.digest)
+                    || read_body(&index.body_path(&variant.digest), &variant.digest)
+                        .is_some_and(|body| body.len() == variant.size);
+                let response = match CachedResponse::from_metadata(variant.metadata) {
+                    Some(response) if intact => response,
+                    _ => {
+                        dropped = true;
+                        continue;
+                    },
+                };
+                verified.insert(variant.digest.clone());
+                index.insert(key.clone(), Variant { response, digest: variant.digest }, variant.size);
+            }
+        }
+        // Start over with a clean index file unless it already holds exactly what was loaded.
+        index.changed.clear();
+        index.compact = replayed || dropped || index.entries.len() != loaded_keys;
+        for file in fs::read_dir(&index.bodies_dir)? {
+            let file = file?;
+            if !index.bodies.contains_key(file.file_name().to_string_lossy().as_ref()) {
+                let _ = fs::remove_file(file.path());
+            }
+        }
+
+        Ok(DiskCache {
+            dir: dir.to_owned(),
+            index: Mutex::new(index),
+            flushing: Mutex::new(()),
+            temp_files: AtomicU64::new(0),
+        })
+    }
+
+    /// The matching variant with its body, or `None` if the body file is gone or corrupt.
+    pub fn get(&self, key: &CacheKey, request_headers: &HeaderMap<HeaderValue>) -> Option<CachedResponse> {
+        let (mut response, path, digest) = {
+            let mut index = self.index.lock().unwrap();
+            index.policy.touch(key);
+            let variant = index.entries
+                .get(key)?
+                .variants
+                .iter()
+                .find(|variant| variant.response.matches_vary(request_headers))?
+                .clone();
+            *index.hits.entry(key.clone()).or_default() += 1;
+            let path = index.body_path(&variant.digest);
+            (variant.response, path, variant.digest)
+        };
+        match read_body(&path, &digest) {
+            Some(body) => {
+                response.set_body(body);
+                Some(response)
+            },
+            None => {
+                self.index.lock().unwrap().remove(key);
+                None
+            },
+        }
+    }
+
+    pub fn insert(&self, key: CacheKey, cached: &CachedResponse) -> io::Result<()> {
+        if cached.metadata().is_none() {
+            return Ok(());
+        }
+        // Checked before the body is written, so that no file is left behind for it.
+        {
+            let mut index = self.index.lock().unwrap();
+            if cached.size() > index.max_bytes {
+                index.rejections += 1;
+                return Ok(());
+            }
+        }
+        let digest = hex(&Sha256::digest(cached.body()));
+        let path = self.dir.join(BODIES_DIR).join(&digest);
+        if !path.exists() {
+            // Write under a unique name first so readers never see a partial file.
+            let temp = self.dir.join(BODIES_DIR).join(format!(
+                "{}.{}.tmp",
+                digest,
+                self.temp_files.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::write(&temp, cached.body())?;
+            fs::rename(&temp, &path)?;
+        }
+        let mut response = cached.clone();
+        response.set_body(Bytes::new());
+        self.index.lock().unwrap().insert(key, Variant { response, digest }, cached.size());
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &CacheKey) {
+        self.index.lock().unwrap().remove(key);
+    }
+
+    /// Removes every method and variant stored for the URL of `target`.
+    pub fn remove_url(&self, target: &CacheKey) {
+        let mut index = self.index.lock().unwrap();
+        for key in index.urls.same_url(target) {
+            index.remove(&key);
+        }
+    }
+
+    pub fn keys(&self) -> Vec<CacheKey> {
+        self.index.lock().unwrap().entries.keys().cloned().collect()
+    }
+
+    pub fn entries(&self, now: SystemTime) -> Vec<EntryInfo> {
+        let index = self.index.lock().unwrap();
+        index.entries
+            .iter()
+            .map(|(key, entry)| EntryInfo {
+                key: key.clone(),
+                hits: index.hits.get(key).copied().unwrap_or(0),
+                variants: entry.variants
+                    .iter()
+                    .map(|variant| {
+                        let size = index.bodies.get(&variant.digest).map_or(0, |body| body.size);
+                        VariantInfo::new(&variant.response, size, now)
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    pub fn remove_expired(&self, now: SystemTime) {
+        self.index.lock().unwrap().remove_expired(now);
+    }
+
+    /// Appends the entries changed since the last flush to the journal, or rewrites the index
+    /// once the journal has grown larger than it. Only copying the entries holds the index
+    /// lock, serializing and writing them doesn't.
+    pub fn flush(&self) -> io::Result<()> {
+        let _flushing = self.flushing.lock().unwrap();
+        let (snapshot, compact) = {
+            let mut index = self.index.lock().unwrap();
+            let compact = index.compact
+                || index.journaled + index.changed.len() > index.entries.len().max(MIN_JOURNAL_RECORDS);
+            let keys: Vec<CacheKey> = if compact {
+                index.changed.clear();
+                index.entries.keys().cloned().collect()
+            } else {
+                index.changed.drain().collect()
+            };
+            if !compact && keys.is_empty() {
+                return Ok(());
+            }
+            if compact {
+                index.compact = false;
+                index.journaled = 0;
+            } else {
+                index.journaled += keys.len();
+            }
+            let snapshot: Vec<(CacheKey, Vec<(Variant, usize)>)> = keys
+                .into_iter()
+                .map(|key| {
+                    let variants = index.entries.get(&key).map_or_else(Vec::new, |entry| {
+                        entry.variants
+                            .iter()
+                            .filter_map(|variant| Some((variant.clone(), index.bodies.get(&variant.digest)?.size)))
+                            .collect()
+                    });
+                    (key, variants)
+                })
+                .collect();
+            (snapshot, compact)
+        };
+        let records: Vec<IndexEntry> = snapshot
+            .into_iter()
+            .map(|(key, variants)| IndexEntry {
+                key: KeyRecord::new(&key),
+                variants: variants
+                    .into_iter()
+                    .filter_map(|(variant, size)| {
+                        Some(VariantRecord { metadata: variant.response.metadata()?, digest: variant.digest, size })
+                    })
+                    .collect(),
+            })
+            .collect();
+        let written = if compact { self.write_index(&records) } else { self.append_journal(&records) };
+        if written.is_err() {
+            // The journal may end in a partial line now, so it is replaced as a whole.
+            self.index.lock().unwrap().compact = true;
+        }
+        written
+    }
+
+    fn append_journal(&self, records: &[IndexEntry]) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut lines, record).map_err(io::Error::other)?;
+            lines.push(b'\n');
+        }
+        let mut journal = OpenOptions::new().create(true).append(true).open(self.dir.join(JOURNAL_FILE))?;
+        journal.write_all(&lines)
+    }
+
+    fn write_index(&self, records: &[IndexEntry]) -> io::Result<()> {
+        let contents = serde_json::to_vec(records).map_err(io::Error::other)?;
+        let temp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        fs::write(&temp, contents)?;
+        // A crash before the rename leaves the previous index without its journal, which is
+        // outdated but consistent: entries whose bodies are gone are dropped when loading.
+        match fs::remove_file(self.dir.join(JOURNAL_FILE)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {},
+        }
+        fs::rename(&temp, self.dir.join(INDEX_FILE))
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let index = self.index.lock().unwrap();
+        CacheStats {
+            entries: index.entries.len(),
+            bytes: index.bytes,
+            evictions: index.evictions,
+            rejections: index.rejections,
+        }
+    }
+}
+
+impl Index {
+    fn body_path(&self, digest: &str) -> PathBuf {
+        self.bodies_dir.join(digest)
+    }
+
+    fn insert(&mut self, key: CacheKey, variant: Variant, size: usize) {
+        if size > self.max_bytes {
+            self.rejections += 1;
+            return;
+        }
+        // Reference the body first so that evicting an entry sharing it can't delete the file.
+        self.retain(&variant.digest, size);
+        // A replaced entry keeps its eviction state, so that refreshing it doesn't make it a victim.
+        let variants = match self.entries.remove(&key) {
+            Some(entry) => {
+                self.expiries.unschedule(&entry.expiry);
+                entry.variants
+            },
+            None => Vec::new(),
+        };
+        let (mut variants, replaced): (Vec<Variant>, Vec<Variant>) = variants
+            .into_iter()
+            .partition(|other| other.response.is_sibling_variant(&variant.response));
+        self.release(&replaced);
+        variants.push(variant);
+
+        while self.entries.len() >= self.max_entries || self.bytes > self.max_bytes {
+            let victim = match self.policy.victim() {
+                Some(victim) => victim,
+                None => break,
+            };
+            if victim == key {
+                self.policy.remove(&key);
+                continue;
+            }
+            if !self.policy.admit(&key, &victim) {
+                self.rejections += 1;
+                self.release(&variants);
+                self.urls.remove(&key);
+                self.hits.remove(&key);
+                self.policy.remove(&key);
+                self.changed.insert(key);
+                return;
+            }
+            self.remove(&victim);
+            self.evictions += 1;
+        }
+        self.policy.insert(&key);
+        self.changed.insert(key.clone());
+        self.schedule(key, variants);
+    }
+
+    fn remove(&mut self, key: &CacheKey) -> Option<Vec<Variant>> {
+        let entry = self.entries.remove(key)?;
+        self.expiries.unschedule(&entry.expiry);
+        self.urls.remove(key);
+        self.hits.remove(key);
+        self.policy.remove(key);
+        self.release(&entry.variants);
+        self.changed.insert(key.clone());
+        Some(entry.variants)
+    }
+
+    fn retain(&mut self, digest: &str, size: usize) {
+        match self.bodies.get_mut(digest) {
+            Some(body) => body.references += 1,
+            None => {
+                self.bytes += size;
+                self.bodies.insert(digest.to_owned(), BodyFile { size, references: 1 });
+            },
+        }
+    }
+
+    // Drops the variants' references to their bodies, deleting files no longer used.
+    fn release(&mut self, variants: &[Variant]) {
+        for variant in variants {
+            let body = match self.bodies.get_mut(&variant.digest) {
+                Some(body) => body,
+                None => continue,
+            };
+            body.references -= 1;
+            if body.references == 0 {
+                self.bytes -= body.size;
+                self.bodies.remove(&variant.digest);
+                let _ = fs::remove_file(self.body_path(&variant.digest));
+            }
+        }
+    }
+
+    fn remove_expired(&mut self, now: SystemTime) {
+        while let Some(key) = self.expiries.pop_due(now) {
+            let variants = match self.entries.remove(&key) {
+                Some(entry) => entry.variants,
+                None => continue,
+            };
+            let (retained, expired): (Vec<Variant>, Vec<Variant>) =
+                variants.into_iter().partition(|variant| variant.response.is_retained(now));
+            self.release(&expired);
+            self.changed.insert(key.clone());
+            if retained.is_empty() {
+                self.urls.remove(&key);
+                self.hits.remove(&key);
+                self.policy.remove(&key);
+            } else {
+                self.schedule(key, retained);
+            }
+        }
+    }
+
+    fn schedule(&mut self, key: CacheKey, variants: Vec<Variant>) {
+        let expiry = self.expiries.schedule(&key, variants.iter().map(|variant| &variant.response));
+        self.urls.insert(&key);
+        self.entries.insert(key, Entry { variants, expiry });
+    }
+}
+
+impl KeyRecord {
+    fn new(key: &CacheKey) -> KeyRecord {
+        KeyRecord {
+            method: key.method.as_str().to_owned(),
+            origin: key.origin.clone(),
+            path: key.path.clone(),
+            query: key.query.clone(),
+            headers: key.headers.iter().map(|(name, value)| (name.as_str().to_owned(), value.clone())).collect(),
+            body_sha256: key.body_digest.map(|digest| hex(&digest)),
+        }
+    }
+
+    fn into_key(self) -> Option<CacheKey> {
+        let mut headers = Vec::with_capacity(self.headers.len());
+        for (name, value) in self.headers {
+            headers.push((HeaderName::try_from(name).ok()?, value));
+        }
+        let body_digest = match self.body_sha256 {
+            Some(digest) => Some(unhex(&digest)?),
+            None => None,
+        };
+        Some(CacheKey {
+            method: Method::from_bytes(self.method.as_bytes()).ok()?,
+            origin: self.origin,
+            path: self.path,
+            query: self.query,
+            headers,
+            body_digest,
+        })
+    }
+}
+
+fn read_body(path: &Path, digest: &str) -> Option<Bytes> {
+    let body = fs::read(path).ok()?;
+    if hex(&Sha256::digest(&body)) != digest {
+        return None;
+    }
+    Some(Bytes::from(body))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(digest: &str) -> Option<[u8; 32]> {
+    let mut bytes = [0; 32];
+    if digest.len() != 2 * bytes.len() {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(digest.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::{Request, Response};
+
+    use super::*;
+    use crate::{
+        cache_key::{DefaultKeyBuilder, KeyBuilder},
+        cached_response::FreshnessDefaults,
+        eviction::PolicyKind,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("proxy-with-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &Path, max_entries: usize, max_bytes: usize) -> DiskCache {
+        DiskCache::open(dir, max_entries, max_bytes, PolicyKind::Lru.build(max_entries)).unwrap()
+    }
+
+    fn key(path: &str) -> CacheKey {
+        let (parts, _) = Request::get(path).body(()).unwrap().into_parts();
+        DefaultKeyBuilder::default().build(&parts, "http://example.com")
+    }
+
+    fn cached(body: &'static str) -> CachedResponse {
+        let (response, _) = Response::builder().header("cache-control", "max-age=60").body(()).unwrap().into_parts();
+        let now = SystemTime::now();
+        let defaults = FreshnessDefaults {
+            ttl: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+        };
+        let mut cached = CachedResponse::new(&HeaderMap::new(), &response, now, now, defaults);
+        cached.set_body(Bytes::from_static(body.as_bytes()));
+        cached
+    }
+
+    fn body(disk: &DiskCache, path: &str) -> Option<Bytes> {
+        disk.get(&key(path), &HeaderMap::new()).map(|cached| cached.body().clone())
+    }
+
+    fn body_files(dir: &Path) -> usize {
+        fs::read_dir(dir.join(BODIES_DIR)).unwrap().count()
+    }
+
+    #[test]
+    fn reloads_entries_from_the_index_and_journal() {
+        let dir = temp_dir("reload");
+        let disk = open(&dir, 10, 1000);
+        disk.insert(key("/a"), &cached("first")).unwrap();
+        disk.insert(key("/b"), &cached("second")).unwrap();
+        disk.flush().unwrap();
+        disk.remove(&key("/a"));
+        disk.flush().unwrap();
+        assert!(!dir.join(INDEX_FILE).exists());
+        drop(disk);
+
+        // A line cut short by a crash doesn't keep the rest from loading.
+        let mut journal = OpenOptions::new().append(true).open(dir.join(JOURNAL_FILE)).unwrap();
+        journal.write_all(b"{\"key\":").unwrap();
+        let disk = open(&dir, 10, 1000);
+        assert_eq!(body(&disk, "/a"), None);
+        assert_eq!(body(&disk, "/b").unwrap(), "second");
+        assert_eq!(body_files(&dir), 1);
+
+        // The first flush after replaying the journal folds it into the index.
+        disk.flush().unwrap();
+        assert!(dir.join(INDEX_FILE).exists());
+        assert!(!dir.join(JOURNAL_FILE).exists());
+        drop(disk);
+        let disk = open(&dir, 10, 1000);
+        assert_eq!(body(&disk, "/b").unwrap(), "second");
+        assert_eq!(disk.stats(), CacheStats { entries: 1, bytes: 6, evictions: 0, rejections: 0 });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_damaged_bodies_and_unreferenced_files() {
+        let dir = temp_dir("verify");
+        let disk = open(&dir, 10, 1000);
+        disk.insert(key("/a"), &cached("first")).unwrap();
+        disk.insert(key("/b"), &cached("second")).unwrap();
+        disk.flush().unwrap();
+        drop(disk);
+
+        let damaged = dir.join(BODIES_DIR).join(hex(&Sha256::digest(b"first")));
+        fs::write(&damaged, "changed").unwrap();
+        fs::write(dir.join(BODIES_DIR).join("unreferenced"), "left over").unwrap();
+        let disk = open(&dir, 10, 1000);
+        assert_eq!(body(&disk, "/a"), None);
+        assert_eq!(body(&disk, "/b").unwrap(), "second");
+        assert_eq!(body_files(&dir), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_shared_bodies_until_the_last_entry_is_gone() {
+        let dir = temp_dir("shared");
+        let disk = open(&dir, 10, 1000);
+        disk.insert(key("/a"), &cached("same")).unwrap();
+        disk.insert(key("/b"), &cached("same")).unwrap();
+        assert_eq!(body_files(&dir), 1);
+        assert_eq!(disk.stats().bytes, 4);
+
+        disk.remove(&key("/a"));
+        assert_eq!(body(&disk, "/b").unwrap(), "same");
+        assert_eq!(body_files(&dir), 1);
+        // Replacing the last reference releases the old body.
+        disk.insert(key("/b"), &cached("other")).unwrap();
+        assert_eq!(body_files(&dir), 1);
+        disk.remove(&key("/b"));
+        assert_eq!(body_files(&dir), 0);
+        assert_eq!(disk.stats().bytes, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_and_rejects_without_leaving_files() {
+        let dir = temp_dir("evict");
+        let disk = open(&dir, 2, 10);
+        disk.insert(key("/a"), &cached("a")).unwrap();
+        disk.insert(key("/b"), &cached("b")).unwrap();
+        assert!(body(&disk, "/a").is_some());
+        disk.insert(key("/c"), &cached("c")).unwrap();
+        assert_eq!(body(&disk, "/b"), None);
+        assert_eq!(body_files(&dir), 2);
+
+        disk.insert(key("/big"), &cached("more than ten")).unwrap();
+        assert_eq!(body(&disk, "/big"), None);
+        assert_eq!(body_files(&dir), 2);
+        assert_eq!(disk.stats(), CacheStats { entries: 2, bytes: 2, evictions: 1, rejections: 1 });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
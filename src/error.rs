@@ -0,0 +1,248 @@
+use std::{error::Error as StdError, fmt, io};
+
+use hyper::{header, Body, Response, StatusCode};
+use serde_json::json;
+
+use crate::access::{DeniedAddress, ResolveError};
+
+
+pub const VIA: &str = "1.1 proxy-with-cache";
+pub const ERROR_HEADER: &str = "x-proxy-error";
+
+
+#[derive(Debug)]
+pub enum ProxyError {
+    NoRoute,
+    BadTarget(String),
+    BadRequestBody(String),
+    Forbidden { origin: String, reason: String },
+    NotCached,
+    Dns(String),
+    ConnectTimeout,
+    Connect(String),
+    Tls(String),
+    UpstreamTimeout,
+    UpstreamReset(String),
+    Upstream(String),
+}
+
+impl ProxyError {
+    /// Classifies a failed upstream request by walking the error's source chain.
+    pub fn from_upstream(error: hyper::Error, origin: &str) -> ProxyError {
+        if let Some(DeniedAddress(address)) = find_cause(&error) {
+            return ProxyError::Forbidden {
+                origin: origin.to_owned(),
+                reason: DeniedAddress(*address).to_string(),
+            };
+        }
+        let detail = chain_to_string(&error);
+        if error.is_connect() {
+            if io_error_kind(&error) == Some(io::ErrorKind::TimedOut) {
+                return ProxyError::ConnectTimeout;
+            }
+            // hyper-rustls hands over TLS failures as an `io::Error`, while HttpConnector's own
+            // error type carries the failures of resolving and connecting.
+            if error.source().is_some_and(|source| source.is::<io::Error>()) {
+                return ProxyError::Tls(detail);
+            }
+            if find_cause::<ResolveError>(&error).is_some() {
+                return ProxyError::Dns(detail);
+            }
+            return ProxyError::Connect(detail);
+        }
+        let reset = matches!(
+            io_error_kind(&error),
+            Some(io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe | io::ErrorKind::UnexpectedEof)
+        );
+        if reset || error.is_incomplete_message() || error.is_closed() || error.is_canceled() {
+            return ProxyError::UpstreamReset(detail);
+        }
+        ProxyError::Upstream(detail)
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::NoRoute => StatusCode::NOT_FOUND,
+            ProxyError::BadTarget(_) | ProxyError::BadRequestBody(_) => StatusCode::BAD_REQUEST,
+            ProxyError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            ProxyError::NotCached | ProxyError::ConnectTimeout | ProxyError::UpstreamTimeout => {
+                StatusCode::GATEWAY_TIMEOUT
+            },
+            ProxyError::Dns(_)
+            | ProxyError::Connect(_)
+            | ProxyError::Tls(_)
+            | ProxyError::UpstreamReset(_)
+            | ProxyError::Upstream(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProxyError::NoRoute => "no_route",
+            ProxyError::BadTarget(_) => "bad_target",
+            ProxyError::BadRequestBody(_) => "bad_request_body",
+            ProxyError::Forbidden { .. } => "forbidden_upstream",
+            ProxyError::NotCached => "not_cached",
+            ProxyError::Dns(_) => "dns_failure",
+            ProxyError::ConnectTimeout => "connect_timeout",
+            ProxyError::Connect(_) => "connect_failure",
+            ProxyError::Tls(_) => "tls_failure",
+            ProxyError::UpstreamTimeout => "upstream_timeout",
+            ProxyError::UpstreamReset(_) => "upstream_reset",
+            ProxyError::Upstream(_) => "upstream_error",
+        }
+    }
+
+    /// An RFC 9457 problem details response.
+    pub fn into_response(self) -> Response<Body> {
+        let status = self.status();
+        let problem = json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or_default(),
+            "status": status.as_u16(),
+            "detail": self.to_string(),
+            "code": self.code(),
+        });
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/problem+json")
+            .header(header::VIA, VIA)
+            .header(ERROR_HEADER, self.code())
+            .body(Body::from(problem.to_string()))
+            .unwrap()
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyError::NoRoute => write!(f, "no upstream route matches the request"),
+            ProxyError::BadTarget(target) => write!(f, "invalid upstream target {}", target),
+            ProxyError::BadRequestBody(error) => write!(f, "failed to read the request body: {}", error),
+            ProxyError::Forbidden { origin, reason } => write!(f, "upstream {} is not allowed: {}", origin, reason),
+            ProxyError::NotCached => write!(f, "only-if-cached was requested and no usable response is stored"),
+            ProxyError::Dns(error) => write!(f, "upstream name resolution failed: {}", error),
+            ProxyError::ConnectTimeout => write!(f, "timed out connecting to the upstream"),
+            ProxyError::Connect(error) => write!(f, "failed to connect to the upstream: {}", error),
+            ProxyError::Tls(error) => write!(f, "TLS handshake with the upstream failed: {}", error),
+            ProxyError::UpstreamTimeout => write!(f, "the upstream did not respond in time"),
+            ProxyError::UpstreamReset(error) => write!(f, "the upstream closed the connection: {}", error),
+            ProxyError::Upstream(error) => write!(f, "upstream request failed: {}", error),
+        }
+    }
+}
+
+impl StdError for ProxyError {}
+
+fn io_error_kind(error: &(dyn StdError + 'static)) -> Option<io::ErrorKind> {
+    find_cause::<io::Error>(error).map(io::Error::kind)
+}
+
+/// Finds an error of type `E` anywhere in an error's source chain.
+fn find_cause<'a, E: StdError + 'static>(error: &'a (dyn StdError + 'static)) -> Option<&'a E> {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(cause) = error.downcast_ref::<E>() {
+            return Some(cause);
+        }
+        // io::Error::source skips the wrapped error itself, so unwrap it explicitly.
+        if let Some(cause) = error
+            .downcast_ref::<io::Error>()
+            .and_then(|error| error.get_ref())
+            .and_then(|inner| inner.downcast_ref::<E>())
+        {
+            return Some(cause);
+        }
+        source = error.source();
+    }
+    None
+}
+
+fn chain_to_string(error: &(dyn StdError + 'static)) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        let cause = error.to_string();
+        if !message.contains(&cause) {
+            message.push_str(": ");
+            message.push_str(&cause);
+        }
+        source = error.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{access, config::UpstreamConfig, controller};
+
+    // Fails a GET through the same connector stack the proxy uses.
+    async fn fail(uri: String, denied_networks: Vec<access::Network>, connect_timeout: Duration) -> ProxyError {
+        let config = UpstreamConfig { connect_timeout, request_timeout: Duration::from_secs(5) };
+        let client = controller::upstream_client(&config, denied_networks);
+        let error = client.get(uri.parse().unwrap()).await.unwrap_err();
+        ProxyError::from_upstream(error, "upstream")
+    }
+
+    // A server that answers every connection with `reply` and then closes it.
+    fn serve_once(reply: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.read(&mut [0; 1024]);
+                let _ = stream.write_all(reply);
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn classifies_resolver_failures() {
+        let error = fail("http://nonexistent.invalid/".to_owned(), Vec::new(), Duration::from_secs(5)).await;
+        assert!(matches!(error, ProxyError::Dns(_)), "{:?}", error);
+        assert_eq!(error.status(), StatusCode::BAD_GATEWAY);
+
+        let error = fail("http://localhost:1/".to_owned(), access::private_networks(), Duration::from_secs(5)).await;
+        let denied = matches!(&error, ProxyError::Forbidden { reason, .. } if reason.contains("denied network"));
+        assert!(denied, "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn classifies_connect_failures() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let error = fail(format!("http://{}/", closed), Vec::new(), Duration::from_secs(5)).await;
+        assert!(matches!(error, ProxyError::Connect(_)), "{:?}", error);
+
+        // With a backlog of one, connections past the first two wait for a SYN-ACK that never comes.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let full = listener.local_addr().unwrap();
+        let _waiting: Vec<_> = (0..3)
+            .filter_map(|_| TcpStream::connect_timeout(&full, Duration::from_millis(100)).ok())
+            .collect();
+        let error = fail(format!("http://{}/", full), Vec::new(), Duration::from_millis(100)).await;
+        assert!(matches!(error, ProxyError::ConnectTimeout), "{:?}", error);
+        assert_eq!(error.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn classifies_tls_failures_and_resets() {
+        let plain_http = serve_once(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n");
+        let error = fail(format!("https://{}/", plain_http), Vec::new(), Duration::from_secs(5)).await;
+        assert!(matches!(error, ProxyError::Tls(_)), "{:?}", error);
+
+        let hang_up = serve_once(b"");
+        let error = fail(format!("http://{}/", hang_up), Vec::new(), Duration::from_secs(5)).await;
+        assert!(matches!(error, ProxyError::UpstreamReset(_)), "{:?}", error);
+    }
+}
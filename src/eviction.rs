@@ -0,0 +1,316 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+use crate::cache_key::CacheKey;
+
+
+/// Decides which entry leaves a full cache. Policies only track keys, the cache owns the data.
+pub trait EvictionPolicy: Send {
+    /// Called on every lookup, whether or not the key is cached.
+    fn touch(&mut self, key: &CacheKey);
+    /// Starts tracking `key`. A key already tracked keeps its state.
+    fn insert(&mut self, key: &CacheKey);
+    fn remove(&mut self, key: &CacheKey);
+    fn victim(&self) -> Option<CacheKey>;
+
+    /// Whether `candidate` is worth evicting `victim` for.
+    fn admit(&self, _candidate: &CacheKey, _victim: &CacheKey) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyKind {
+    Lru,
+    Lfu,
+    TinyLfu,
+}
+
+impl PolicyKind {
+    pub fn build(self, capacity: usize) -> Box<dyn EvictionPolicy> {
+        match self {
+            PolicyKind::Lru => Box::<Lru>::default(),
+            PolicyKind::Lfu => Box::<Lfu>::default(),
+            PolicyKind::TinyLfu => Box::new(TinyLfu::new(capacity)),
+        }
+    }
+}
+
+impl FromStr for PolicyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PolicyKind, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "lru" => Ok(PolicyKind::Lru),
+            "lfu" => Ok(PolicyKind::Lfu),
+            "tinylfu" | "tiny-lfu" => Ok(PolicyKind::TinyLfu),
+            _ => Err(format!("unknown eviction policy `{}`, expected one of lru, lfu, tinylfu", s)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Lru {
+    tick: u64,
+    ticks: HashMap<CacheKey, u64>,
+    order: BTreeMap<u64, CacheKey>,
+}
+
+impl EvictionPolicy for Lru {
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(tick) = self.ticks.get_mut(key) {
+            self.order.remove(tick);
+            self.tick += 1;
+            *tick = self.tick;
+            self.order.insert(self.tick, key.clone());
+        }
+    }
+
+    fn insert(&mut self, key: &CacheKey) {
+        if self.ticks.contains_key(key) {
+            return;
+        }
+        self.tick += 1;
+        self.ticks.insert(key.clone(), self.tick);
+        self.order.insert(self.tick, key.clone());
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn victim(&self) -> Option<CacheKey> {
+        self.order.values().next().cloned()
+    }
+}
+
+/// Least frequently used, ties broken by least recently used.
+#[derive(Default)]
+pub struct Lfu {
+    tick: u64,
+    counts: HashMap<CacheKey, (u64, u64)>,
+    order: BTreeMap<(u64, u64), CacheKey>,
+}
+
+impl EvictionPolicy for Lfu {
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(count) = self.counts.get_mut(key) {
+            self.order.remove(count);
+            self.tick += 1;
+            *count = (count.0 + 1, self.tick);
+            self.order.insert(*count, key.clone());
+        }
+    }
+
+    fn insert(&mut self, key: &CacheKey) {
+        if self.counts.contains_key(key) {
+            return;
+        }
+        self.tick += 1;
+        self.counts.insert(key.clone(), (1, self.tick));
+        self.order.insert((1, self.tick), key.clone());
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(count) = self.counts.remove(key) {
+            self.order.remove(&count);
+        }
+    }
+
+    fn victim(&self) -> Option<CacheKey> {
+        self.order.values().next().cloned()
+    }
+}
+
+/// LRU eviction behind a TinyLFU admission filter: a new entry only displaces the LRU
+/// victim if it has been requested more often recently, which keeps one-hit wonders out.
+pub struct TinyLfu {
+    sketch: CountMinSketch,
+    lru: Lru,
+}
+
+impl TinyLfu {
+    pub fn new(capacity: usize) -> TinyLfu {
+        TinyLfu {
+            sketch: CountMinSketch::new(capacity),
+            lru: Lru::default(),
+        }
+    }
+}
+
+impl EvictionPolicy for TinyLfu {
+    fn touch(&mut self, key: &CacheKey) {
+        self.sketch.increment(key);
+        self.lru.touch(key);
+    }
+
+    fn insert(&mut self, key: &CacheKey) {
+        self.lru.insert(key);
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        self.lru.remove(key);
+    }
+
+    fn victim(&self) -> Option<CacheKey> {
+        self.lru.victim()
+    }
+
+    fn admit(&self, candidate: &CacheKey, victim: &CacheKey) -> bool {
+        self.sketch.estimate(candidate) > self.sketch.estimate(victim)
+    }
+}
+
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_MAX_COUNT: u8 = 15;
+
+struct CountMinSketch {
+    rows: Vec<Vec<u8>>,
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl CountMinSketch {
+    fn new(capacity: usize) -> CountMinSketch {
+        let width = capacity.max(16).next_power_of_two();
+        CountMinSketch {
+            rows: vec![vec![0; width]; SKETCH_DEPTH],
+            mask: width - 1,
+            additions: 0,
+            sample_size: width * 10,
+        }
+    }
+
+    fn index(&self, key: &CacheKey, row: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        key.hash(&mut hasher);
+        hasher.finish() as usize & self.mask
+    }
+
+    fn increment(&mut self, key: &CacheKey) {
+        for row in 0..SKETCH_DEPTH {
+            let index = self.index(key, row);
+            let counter = &mut self.rows[row][index];
+            *counter = (*counter + 1).min(SKETCH_MAX_COUNT);
+        }
+        self.additions += 1;
+        // Halving every counter periodically lets the sketch forget stale popularity.
+        if self.additions >= self.sample_size {
+            self.additions /= 2;
+            for counter in self.rows.iter_mut().flatten() {
+                *counter /= 2;
+            }
+        }
+    }
+
+    fn estimate(&self, key: &CacheKey) -> u8 {
+        (0..SKETCH_DEPTH)
+            .map(|row| self.rows[row][self.index(key, row)])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Request;
+
+    use super::*;
+    use crate::cache_key::{DefaultKeyBuilder, KeyBuilder};
+
+    fn key(path: &str) -> CacheKey {
+        let (parts, _) = Request::get(path).body(()).unwrap().into_parts();
+        DefaultKeyBuilder::default().build(&parts, "http://example.com")
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used() {
+        let mut lru = Lru::default();
+        for path in ["/a", "/b", "/c"] {
+            lru.insert(&key(path));
+        }
+        assert_eq!(lru.victim(), Some(key("/a")));
+        lru.touch(&key("/a"));
+        assert_eq!(lru.victim(), Some(key("/b")));
+        lru.remove(&key("/b"));
+        assert_eq!(lru.victim(), Some(key("/c")));
+        // Touching an untracked key doesn't start tracking it.
+        lru.touch(&key("/d"));
+        lru.remove(&key("/c"));
+        lru.remove(&key("/a"));
+        assert_eq!(lru.victim(), None);
+    }
+
+    #[test]
+    fn lfu_breaks_ties_by_recency() {
+        let mut lfu = Lfu::default();
+        for path in ["/a", "/b", "/c"] {
+            lfu.insert(&key(path));
+        }
+        lfu.touch(&key("/a"));
+        lfu.touch(&key("/a"));
+        lfu.touch(&key("/b"));
+        lfu.touch(&key("/c"));
+        // `/b` and `/c` were used equally often, `/b` less recently.
+        assert_eq!(lfu.victim(), Some(key("/b")));
+        lfu.touch(&key("/b"));
+        assert_eq!(lfu.victim(), Some(key("/c")));
+    }
+
+    #[test]
+    fn inserting_a_tracked_key_keeps_its_state() {
+        let mut lfu = Lfu::default();
+        lfu.insert(&key("/hot"));
+        lfu.touch(&key("/hot"));
+        lfu.touch(&key("/hot"));
+        lfu.insert(&key("/cold"));
+        lfu.insert(&key("/hot"));
+        assert_eq!(lfu.victim(), Some(key("/cold")));
+
+        let mut lru = Lru::default();
+        lru.insert(&key("/a"));
+        lru.insert(&key("/b"));
+        lru.insert(&key("/a"));
+        assert_eq!(lru.victim(), Some(key("/a")));
+    }
+
+    #[test]
+    fn tinylfu_admits_only_more_popular_candidates() {
+        let mut tiny = TinyLfu::new(16);
+        tiny.insert(&key("/resident"));
+        tiny.touch(&key("/resident"));
+        tiny.touch(&key("/resident"));
+        assert_eq!(tiny.victim(), Some(key("/resident")));
+
+        tiny.touch(&key("/new"));
+        assert!(!tiny.admit(&key("/new"), &key("/resident")));
+        tiny.touch(&key("/new"));
+        assert!(!tiny.admit(&key("/new"), &key("/resident")));
+        tiny.touch(&key("/new"));
+        assert!(tiny.admit(&key("/new"), &key("/resident")));
+    }
+
+    #[test]
+    fn sketch_counts_saturate_and_halve() {
+        let mut sketch = CountMinSketch::new(16);
+        assert_eq!(sketch.sample_size, 160);
+        for _ in 0..20 {
+            sketch.increment(&key("/a"));
+        }
+        assert_eq!(sketch.estimate(&key("/a")), SKETCH_MAX_COUNT);
+        assert_eq!(sketch.estimate(&key("/never")), 0);
+
+        for _ in 20..sketch.sample_size {
+            sketch.increment(&key("/a"));
+        }
+        assert_eq!(sketch.additions, 80);
+        assert_eq!(sketch.estimate(&key("/a")), SKETCH_MAX_COUNT / 2);
+    }
+}
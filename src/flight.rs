@@ -0,0 +1,383 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::stream;
+use hyper::{
+    body::{Bytes, HttpBody},
+    Body,
+};
+use tokio::sync::{watch, Notify};
+
+use crate::{cache_key::CacheKey, cached_response::CachedResponse};
+
+
+/// A single upstream fetch shared by every concurrent request for the same cache key.
+///
+/// While the body may still go into the cache it is read as fast as the upstream sends it,
+/// and each subscriber reads the received chunks at its own pace. Past `max_body_size` the
+/// slowest subscriber sets the pace instead, so that the backlog stays bounded.
+pub struct Flight {
+    head: watch::Sender<Option<Option<CachedResponse>>>,
+    state: Mutex<FlightState>,
+    received: Notify,
+    consumed: Notify,
+}
+
+#[derive(Default)]
+struct FlightState {
+    // Chunks not yet read by every subscriber, `chunks[0]` being chunk number `first`.
+    // All of them are kept while the body may still go into the cache.
+    chunks: VecDeque<Bytes>,
+    first: usize,
+    retained: usize,
+    size: usize,
+    overflowed: bool,
+    complete: bool,
+    failed: Option<String>,
+    // The number of the next chunk each subscriber reads.
+    cursors: HashMap<u64, usize>,
+    next_id: u64,
+}
+
+/// A subscriber's place in the flight, given up when its body is dropped.
+struct Subscription {
+    flight: Arc<Flight>,
+    id: u64,
+}
+
+impl Flight {
+    fn new() -> Flight {
+        Flight {
+            head: watch::channel(None).0,
+            state: Mutex::new(FlightState::default()),
+            received: Notify::new(),
+            consumed: Notify::new(),
+        }
+    }
+
+    /// Waits for the leader's response head. `None` means the wait timed out or the
+    /// response can't be shared, and the caller should fetch on its own.
+    pub async fn wait(&self, timeout: Duration) -> Option<CachedResponse> {
+        let mut receiver = self.head.subscribe();
+        let head = match tokio::time::timeout(timeout, receiver.wait_for(Option::is_some)).await {
+            Ok(Ok(head)) => head.clone().flatten(),
+            _ => None,
+        };
+        head
+    }
+
+    pub fn publish(&self, head: Option<CachedResponse>) {
+        self.head.send_replace(Some(head));
+    }
+
+    /// A body replaying the chunks received so far followed by the rest of the stream.
+    pub fn subscribe(self: &Arc<Self>) -> Option<Body> {
+        let mut state = self.state.lock().unwrap();
+        if state.overflowed || state.failed.is_some() {
+            return None;
+        }
+        if state.complete {
+            return Some(Body::wrap_stream(stream::iter(state.chunks.clone().into_iter().map(Ok::<_, io::Error>))));
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        let first = state.first;
+        state.cursors.insert(id, first);
+        let subscription = Subscription { flight: self.clone(), id };
+        Some(Body::wrap_stream(stream::unfold(Some(subscription), |subscription| async move {
+            let subscription = subscription?;
+            match subscription.flight.next_chunk(subscription.id).await {
+                Some(Ok(chunk)) => Some((Ok(chunk), Some(subscription))),
+                Some(Err(error)) => Some((Err(error), None)),
+                None => None,
+            }
+        })))
+    }
+
+    async fn next_chunk(&self, id: u64) -> Option<Result<Bytes, io::Error>> {
+        loop {
+            let mut received = pin!(self.received.notified());
+            received.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                let cursor = match state.cursors.get(&id) {
+                    Some(&cursor) => cursor,
+                    None => return None,
+                };
+                if let Some(chunk) = state.chunks.get(cursor - state.first).cloned() {
+                    state.cursors.insert(id, cursor + 1);
+                    state.trim();
+                    drop(state);
+                    self.consumed.notify_waiters();
+                    return Some(Ok(chunk));
+                }
+                if let Some(error) = &state.failed {
+                    return Some(Err(io::Error::other(error.clone())));
+                }
+                if state.complete {
+                    return None;
+                }
+            }
+            received.await;
+        }
+    }
+
+    pub fn complete_with(&self, body: Bytes) {
+        let mut state = self.state.lock().unwrap();
+        state.size = body.len();
+        state.retained = body.len();
+        state.first = 0;
+        state.chunks = VecDeque::from([body]);
+        state.complete = true;
+        drop(state);
+        self.received.notify_waiters();
+    }
+
+    /// Reads `upstream` for all subscribers. Returns the whole body unless it failed or
+    /// exceeded `max_body_size`. Past that size chunks are only kept until every subscriber
+    /// has read them, and reading waits while more than `max_body_size` is unread.
+    pub async fn distribute(&self, mut upstream: Body, max_body_size: usize) -> Option<Bytes> {
+        while let Some(chunk) = upstream.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(error) => {
+                    self.state.lock().unwrap().failed = Some(error.to_string());
+                    self.received.notify_waiters();
+                    return None;
+                }
+            };
+            {
+                let mut state = self.state.lock().unwrap();
+                state.size += chunk.len();
+                state.retained += chunk.len();
+                state.chunks.push_back(chunk);
+                if state.size > max_body_size {
+                    state.overflowed = true;
+                    state.trim();
+                }
+            }
+            self.received.notify_waiters();
+            if !self.wait_for_subscribers(max_body_size).await {
+                return None;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.complete = true;
+        self.received.notify_waiters();
+        if state.overflowed {
+            return None;
+        }
+        let mut body = Vec::with_capacity(state.size);
+        for chunk in &state.chunks {
+            body.extend_from_slice(chunk);
+        }
+        Some(Bytes::from(body))
+    }
+
+    // Once the body can't be cached, waits until the subscribers have caught up to within
+    // `max_body_size`. False when there is no one left to read for.
+    async fn wait_for_subscribers(&self, max_body_size: usize) -> bool {
+        loop {
+            let mut consumed = pin!(self.consumed.notified());
+            consumed.as_mut().enable();
+            {
+                let state = self.state.lock().unwrap();
+                if !state.overflowed {
+                    return true;
+                }
+                if state.cursors.is_empty() {
+                    return false;
+                }
+                if state.retained <= max_body_size {
+                    return true;
+                }
+            }
+            consumed.await;
+        }
+    }
+}
+
+impl FlightState {
+    // Once the body can't be cached, chunks are only kept for subscribers still to read them.
+    fn trim(&mut self) {
+        if !self.overflowed {
+            return;
+        }
+        let end = self.first + self.chunks.len();
+        let keep_from = self.cursors.values().copied().min().unwrap_or(end);
+        while self.first < keep_from {
+            if let Some(chunk) = self.chunks.pop_front() {
+                self.retained -= chunk.len();
+            }
+            self.first += 1;
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut state = self.flight.state.lock().unwrap();
+        state.cursors.remove(&self.id);
+        state.trim();
+        drop(state);
+        self.flight.consumed.notify_waiters();
+    }
+}
+
+pub enum Role {
+    Leader(FlightGuard),
+    Follower(Arc<Flight>),
+}
+
+#[derive(Default)]
+pub struct Flights {
+    flights: Mutex<HashMap<CacheKey, Arc<Flight>>>,
+}
+
+impl Flights {
+    pub fn join(self: &Arc<Self>, key: &CacheKey) -> Role {
+        let mut flights = self.flights.lock().unwrap();
+        if let Some(flight) = flights.get(key) {
+            return Role::Follower(flight.clone());
+        }
+        let flight = Arc::new(Flight::new());
+        flights.insert(key.clone(), flight.clone());
+        Role::Leader(FlightGuard {
+            flights: self.clone(),
+            key: key.clone(),
+            flight,
+        })
+    }
+}
+
+/// Held by the leader for as long as its flight should accept followers.
+pub struct FlightGuard {
+    flights: Arc<Flights>,
+    key: CacheKey,
+    flight: Arc<Flight>,
+}
+
+impl FlightGuard {
+    pub fn subscribe(&self) -> Option<Body> {
+        self.flight.subscribe()
+    }
+}
+
+impl std::ops::Deref for FlightGuard {
+    type Target = Flight;
+
+    fn deref(&self) -> &Flight {
+        &self.flight
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        // Followers must not keep waiting on a leader that went away without a response.
+        self.flight.head.send_if_modified(|head| {
+            if head.is_none() {
+                *head = Some(None);
+                true
+            } else {
+                false
+            }
+        });
+        let mut flights = self.flights.flights.lock().unwrap();
+        if flights.get(&self.key).is_some_and(|flight| Arc::ptr_eq(flight, &self.flight)) {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    fn upstream(chunks: &[&'static str]) -> Body {
+        let chunks: Vec<Result<Bytes, io::Error>> = chunks.iter().map(|chunk| Ok(Bytes::from(*chunk))).collect();
+        Body::wrap_stream(stream::iter(chunks))
+    }
+
+    async fn read(body: Body) -> Result<Bytes, hyper::Error> {
+        hyper::body::to_bytes(body).await
+    }
+
+    #[tokio::test]
+    async fn returns_the_body_for_the_cache_and_replays_it() {
+        let flight = Arc::new(Flight::new());
+        let leader = flight.subscribe().unwrap();
+        let distributing = tokio::spawn({
+            let flight = flight.clone();
+            async move { flight.distribute(upstream(&["abc", "def"]), 100).await }
+        });
+        assert_eq!(read(leader).await.unwrap(), "abcdef");
+        assert_eq!(distributing.await.unwrap().unwrap(), "abcdef");
+        // Late subscribers get the complete body.
+        assert_eq!(read(flight.subscribe().unwrap()).await.unwrap(), "abcdef");
+    }
+
+    #[tokio::test]
+    async fn a_slow_subscriber_gets_all_of_an_oversized_body() {
+        let flight = Arc::new(Flight::new());
+        let slow = flight.subscribe().unwrap();
+        let fast = flight.subscribe().unwrap();
+        let chunks = ["0123456789", "abcdefghij", "ABCDEFGHIJ", "9876543210"];
+        let distributing = tokio::spawn({
+            let flight = flight.clone();
+            async move { flight.distribute(upstream(&chunks), 15).await }
+        });
+
+        // The fast subscriber can only get ahead by the limit before the slow one is waited for.
+        let mut fast = fast;
+        let mut fast_read = Vec::new();
+        for _ in 0..2 {
+            fast_read.extend_from_slice(&fast.next().await.unwrap().unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!distributing.is_finished());
+        assert!(flight.subscribe().is_none());
+
+        // Past the limit each reader waits for the other, so both have to read at once.
+        let read_fast = async {
+            while let Some(chunk) = fast.next().await {
+                fast_read.extend_from_slice(&chunk.unwrap());
+            }
+        };
+        let (slow_read, _) = tokio::join!(read(slow), read_fast);
+        assert_eq!(slow_read.unwrap(), chunks.concat());
+        assert_eq!(fast_read, chunks.concat().as_bytes());
+        assert_eq!(distributing.await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn stops_reading_once_every_subscriber_is_gone() {
+        let flight = Arc::new(Flight::new());
+        let subscriber = flight.subscribe().unwrap();
+        let distributing = tokio::spawn({
+            let flight = flight.clone();
+            async move { flight.distribute(upstream(&["0123456789", "0123456789", "0123456789"]), 15).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!distributing.is_finished());
+        drop(subscriber);
+        assert_eq!(distributing.await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn upstream_errors_reach_subscribers() {
+        let flight = Arc::new(Flight::new());
+        let subscriber = flight.subscribe().unwrap();
+        let chunks: Vec<Result<Bytes, io::Error>> = vec![Ok(Bytes::from("abc")), Err(io::Error::other("reset"))];
+        assert_eq!(flight.distribute(Body::wrap_stream(stream::iter(chunks)), 100).await, None);
+        assert!(read(subscriber).await.is_err());
+        assert!(flight.subscribe().is_none());
+    }
+}
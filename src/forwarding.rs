@@ -0,0 +1,275 @@
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::{
+    header::{self, HeaderName},
+    http::HeaderValue,
+    HeaderMap, Uri, Version,
+};
+
+use crate::{
+    access::Network,
+    config::{ForwardedHeaders, ForwardingConfig},
+};
+
+
+const PSEUDONYM: &str = "proxy-with-cache";
+
+// RFC 9110 section 7.6.1, plus the proxy credentials, which are meant for this hop only.
+const HOP_BY_HOP: [HeaderName; 9] = [
+    header::CONNECTION,
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+];
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+
+/// The client connection a request arrived on, kept in its extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub tls: bool,
+}
+
+impl Peer {
+    fn proto(&self) -> &'static str {
+        if self.tls { "https" } else { "http" }
+    }
+}
+
+/// Rewrites the headers of messages passing through the proxy: hop-by-hop headers are
+/// dropped in both directions, `Via` is appended, and requests get the upstream's `Host`
+/// and the configured forwarding headers.
+pub struct Forwarding {
+    headers: ForwardedHeaders,
+    trusted_proxies: Vec<Network>,
+}
+
+impl Forwarding {
+    pub fn new(config: &ForwardingConfig) -> Forwarding {
+        Forwarding {
+            headers: config.headers,
+            trusted_proxies: config.trusted_proxies.clone(),
+        }
+    }
+
+    /// Prepares the headers of a request received over `version` from `peer` for the
+    /// upstream at `uri`. Forwarding headers sent by a trusted proxy are extended, those sent
+    /// by anyone else are replaced.
+    pub fn request(&self, headers: &mut HeaderMap<HeaderValue>, version: Version, uri: &Uri, peer: Option<Peer>) {
+        remove_hop_by_hop(headers);
+        let host = headers.get(header::HOST).cloned();
+        let trusted = peer.is_some_and(|peer| {
+            self.trusted_proxies.iter().any(|network| network.contains(peer.addr.ip()))
+        });
+        if !trusted {
+            for name in [X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_HOST, header::FORWARDED] {
+                headers.remove(name);
+            }
+        }
+
+        if let Some(peer) = peer.filter(|_| self.headers != ForwardedHeaders::None) {
+            let ip = peer.addr.ip().to_canonical();
+            if matches!(self.headers, ForwardedHeaders::XForwarded | ForwardedHeaders::Both) {
+                extend(headers, X_FORWARDED_FOR, &ip.to_string());
+                if !headers.contains_key(X_FORWARDED_PROTO) {
+                    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(peer.proto()));
+                }
+                if let Some(host) = host.as_ref().filter(|_| !headers.contains_key(X_FORWARDED_HOST)) {
+                    headers.insert(X_FORWARDED_HOST, host.clone());
+                }
+            }
+            if matches!(self.headers, ForwardedHeaders::Forwarded | ForwardedHeaders::Both) {
+                let mut element = match ip {
+                    IpAddr::V4(ip) => format!("for={}", ip),
+                    IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+                };
+                if let Some(host) = host.as_ref().and_then(|host| host.to_str().ok()) {
+                    element.push_str(&format!(";host=\"{}\"", host));
+                }
+                element.push_str(";proto=");
+                element.push_str(peer.proto());
+                extend(headers, header::FORWARDED, &element);
+            }
+        }
+
+        if let Some(authority) = uri.authority() {
+            if let Ok(value) = HeaderValue::from_str(authority.as_str()) {
+                headers.insert(header::HOST, value);
+            }
+        }
+        headers.append(header::VIA, via(version));
+    }
+
+    /// Prepares the headers of a response received from an upstream over `version`.
+    pub fn response(&self, headers: &mut HeaderMap<HeaderValue>, version: Version) {
+        remove_hop_by_hop(headers);
+        headers.append(header::VIA, via(version));
+    }
+}
+
+/// Removes the headers that only apply to a single connection, including any named in
+/// `Connection`.
+pub fn remove_hop_by_hop(headers: &mut HeaderMap<HeaderValue>) {
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in named.iter().chain(&HOP_BY_HOP) {
+        headers.remove(name);
+    }
+}
+
+/// The `Via` entry for a message received over `version`.
+pub fn via(version: Version) -> HeaderValue {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    HeaderValue::from_str(&format!("{} {}", protocol, PSEUDONYM)).unwrap()
+}
+
+// Appends `element` to the list in the `name` headers, folding them into one.
+fn extend(headers: &mut HeaderMap<HeaderValue>, name: HeaderName, element: &str) {
+    let combined = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .chain([element])
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = HeaderValue::from_str(&combined) {
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarding(headers: ForwardedHeaders) -> Forwarding {
+        Forwarding::new(&ForwardingConfig { headers, trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()] })
+    }
+
+    fn peer(addr: &str, tls: bool) -> Option<Peer> {
+        Some(Peer { addr: addr.parse().unwrap(), tls })
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap<HeaderValue> {
+        pairs
+            .iter()
+            .map(|(name, value)| (HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    // Forwards an HTTP/1.1 request to `upstream()`.
+    fn forward(kind: ForwardedHeaders, headers: &mut HeaderMap<HeaderValue>, peer: Option<Peer>) {
+        forwarding(kind).request(headers, Version::HTTP_11, &upstream(), peer);
+    }
+
+    fn values<'a>(headers: &'a HeaderMap<HeaderValue>, name: &str) -> Vec<&'a str> {
+        headers.get_all(name).iter().map(|value| value.to_str().unwrap()).collect()
+    }
+
+    fn upstream() -> Uri {
+        "https://upstream.example:8443/api".parse().unwrap()
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers_in_both_directions() {
+        let hop_by_hop = [
+            ("connection", "keep-alive, X-Session,  x-trace"),
+            ("connection", "upgrade"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-authorization", "Basic abc"),
+            ("proxy-connection", "keep-alive"),
+            ("te", "trailers"),
+            ("trailer", "x-checksum"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "h2c"),
+            ("x-session", "1"),
+            ("x-trace", "2"),
+            ("accept", "text/html"),
+        ];
+        let mut request = headers(&hop_by_hop);
+        forward(ForwardedHeaders::None, &mut request, peer("192.0.2.1:1000", false));
+        let names: Vec<&str> = request.keys().map(HeaderName::as_str).collect();
+        assert_eq!(names, ["accept", "host", "via"]);
+
+        let mut response = headers(&hop_by_hop);
+        response.append(header::VIA, HeaderValue::from_static("1.0 origin-cache"));
+        forwarding(ForwardedHeaders::None).response(&mut response, Version::HTTP_2);
+        let names: Vec<&str> = response.keys().map(HeaderName::as_str).collect();
+        assert_eq!(names, ["accept", "via"]);
+        assert_eq!(values(&response, "via"), ["1.0 origin-cache", "2 proxy-with-cache"]);
+    }
+
+    #[test]
+    fn rewrites_host_for_the_upstream() {
+        let mut request = headers(&[("host", "proxy.example")]);
+        forwarding(ForwardedHeaders::XForwarded).request(&mut request, Version::HTTP_10, &upstream(), None);
+        assert_eq!(values(&request, "host"), ["upstream.example:8443"]);
+        assert_eq!(values(&request, "via"), ["1.0 proxy-with-cache"]);
+        // Without a peer there is nothing to forward.
+        assert!(!request.contains_key(X_FORWARDED_FOR));
+    }
+
+    #[test]
+    fn replaces_forwarding_headers_from_untrusted_peers() {
+        let sent = [
+            ("host", "proxy.example"),
+            ("x-forwarded-for", "198.51.100.7"),
+            ("x-forwarded-proto", "http"),
+            ("x-forwarded-host", "spoofed.example"),
+            ("forwarded", "for=198.51.100.7"),
+        ];
+        let mut request = headers(&sent);
+        forward(ForwardedHeaders::Both, &mut request, peer("[2001:db8::1]:1000", true));
+        assert_eq!(values(&request, "x-forwarded-for"), ["2001:db8::1"]);
+        assert_eq!(values(&request, "x-forwarded-proto"), ["https"]);
+        assert_eq!(values(&request, "x-forwarded-host"), ["proxy.example"]);
+        assert_eq!(values(&request, "forwarded"), ["for=\"[2001:db8::1]\";host=\"proxy.example\";proto=https"]);
+
+        // Headers that aren't configured are still dropped.
+        let mut request = headers(&sent);
+        forward(ForwardedHeaders::Forwarded, &mut request, peer("192.0.2.1:1000", false));
+        assert!(!request.contains_key(X_FORWARDED_FOR));
+        assert_eq!(values(&request, "forwarded"), ["for=192.0.2.1;host=\"proxy.example\";proto=http"]);
+    }
+
+    #[test]
+    fn extends_forwarding_headers_from_trusted_proxies() {
+        let mut request = headers(&[
+            ("host", "proxy.example"),
+            ("x-forwarded-for", "198.51.100.7"),
+            ("x-forwarded-for", "198.51.100.8"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "public.example"),
+            ("forwarded", "for=198.51.100.7;proto=https"),
+        ]);
+        // IPv4-mapped peers are reported as plain IPv4.
+        let trusted = peer("[::ffff:10.1.2.3]:1000", false);
+        forward(ForwardedHeaders::Both, &mut request, trusted);
+        assert_eq!(values(&request, "x-forwarded-for"), ["198.51.100.7, 198.51.100.8, 10.1.2.3"]);
+        assert_eq!(values(&request, "x-forwarded-proto"), ["https"]);
+        assert_eq!(values(&request, "x-forwarded-host"), ["public.example"]);
+        assert_eq!(
+            values(&request, "forwarded"),
+            ["for=198.51.100.7;proto=https, for=10.1.2.3;host=\"proxy.example\";proto=http"]
+        );
+    }
+}
@@ -0,0 +1,22 @@
+#![deny(warnings)]
+
+pub mod access;
+pub mod admin;
+pub mod cache;
+pub mod cache_control;
+pub mod cache_key;
+pub mod config;
+pub mod cached_response;
+pub mod controller;
+pub mod disk;
+pub mod error;
+pub mod eviction;
+pub mod flight;
+pub mod forwarding;
+pub mod logging;
+pub mod metrics;
+pub mod range;
+pub mod router;
+pub mod store;
+pub mod telemetry;
+pub mod tls;
@@ -0,0 +1,393 @@
+use std::{
+    cell::RefCell,
+    error::Error,
+    future::Future,
+    io::IsTerminal,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    body::{Bytes, HttpBody, SizeHint},
+    header,
+    http::HeaderValue,
+    Body, HeaderMap, Method, Request, Response, StatusCode,
+};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::Span;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    fmt::{self, MakeWriter},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    Layer, Registry,
+};
+
+use crate::{
+    config::{Config, LogFormat, LogLevel, LogRotation},
+    metrics::CacheResult,
+    telemetry,
+};
+
+
+/// Target of the access log events, so that they can be routed apart from the other logs.
+pub const ACCESS_TARGET: &str = "access";
+
+
+tokio::task_local! {
+    static EXCHANGE: RefCell<Exchange>;
+}
+
+/// What handling a request revealed that the access log reports.
+#[derive(Debug, Default)]
+pub struct Exchange {
+    upstream: Option<String>,
+    cache: Option<CacheResult>,
+    upstream_time: Option<Duration>,
+}
+
+/// Flushes the access log file and the trace exporter when dropped.
+pub struct LogGuard {
+    _access_log: Option<WorkerGuard>,
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+/// Installs the global subscriber. Access log events go to the main log unless they have a
+/// file of their own, and spans are exported if OTLP is configured.
+pub fn init(config: &Config) -> Result<LogGuard, Box<dyn Error + Send + Sync>> {
+    let level = LevelFilter::from(config.log_level);
+    let access = &config.access_log;
+    let access_level = |enabled: bool| if enabled { LevelFilter::INFO } else { LevelFilter::OFF };
+    let filter = Targets::new()
+        .with_default(level.min(LevelFilter::WARN))
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+        .with_target(ACCESS_TARGET, access_level(access.enabled && access.path.is_none()));
+    let stdout = format_layer(config.log_format, std::io::stdout, std::io::stdout().is_terminal());
+    let mut layers = vec![stdout.with_filter(filter).boxed()];
+
+    let mut guard = None;
+    if let Some(path) = access.path.as_ref().filter(|_| access.enabled) {
+        let rotation = match access.rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        };
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let file_name = path.file_name().unwrap_or_default();
+        let appender = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix(file_name.to_string_lossy())
+            .build(directory)?;
+        let (writer, worker) = tracing_appender::non_blocking(appender);
+        let filter = Targets::new().with_target(ACCESS_TARGET, LevelFilter::INFO);
+        layers.push(format_layer(config.log_format, writer, false).with_filter(filter).boxed());
+        guard = Some(worker);
+    }
+
+    let tracer_provider = match &config.otlp {
+        Some(otlp) => {
+            let provider = telemetry::tracer_provider(otlp)?;
+            let filter = Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::INFO);
+            layers.push(telemetry::layer(&provider).with_filter(filter).boxed());
+            Some(provider)
+        },
+        None => None,
+    };
+    tracing_subscriber::registry().with(layers).init();
+    Ok(LogGuard { _access_log: guard, tracer_provider })
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = &self.tracer_provider {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Human => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> LevelFilter {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Runs `future` with a fresh `Exchange` that the `record_*` functions fill in. Tasks it
+/// spawns don't record into it.
+pub async fn capture<F: Future>(future: F) -> (F::Output, Exchange) {
+    EXCHANGE
+        .scope(RefCell::new(Exchange::default()), async {
+            let output = future.await;
+            (output, EXCHANGE.with(RefCell::take))
+        })
+        .await
+}
+
+pub fn record_upstream(origin: &str) {
+    let _ = EXCHANGE.try_with(|exchange| exchange.borrow_mut().upstream = Some(origin.to_owned()));
+}
+
+pub fn record_cache_result(result: CacheResult) {
+    let _ = EXCHANGE.try_with(|exchange| exchange.borrow_mut().cache = Some(result));
+}
+
+/// Adds to the time spent waiting for upstream response heads.
+pub fn record_upstream_time(duration: Duration) {
+    let _ = EXCHANGE.try_with(|exchange| {
+        let mut exchange = exchange.borrow_mut();
+        exchange.upstream_time = Some(exchange.upstream_time.unwrap_or_default() + duration);
+    });
+}
+
+/// The access log entry of one request, completed once its response body is done.
+pub struct AccessLog {
+    client: SocketAddr,
+    method: Method,
+    url: String,
+    start: Instant,
+}
+
+impl AccessLog {
+    pub fn new(client: SocketAddr, req: &Request<Body>) -> AccessLog {
+        AccessLog {
+            client,
+            method: req.method().clone(),
+            url: req.uri().to_string(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Wraps the response body so that the entry is written when it has been sent or dropped.
+    /// `span` stays open until then, with a child span covering the body transfer.
+    pub fn attach(self, response: Response<Body>, exchange: Exchange, span: Span) -> Response<LoggedBody> {
+        let status = response.status();
+        let head_time = self.start.elapsed();
+        let length = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        response.map(|body| LoggedBody {
+            body,
+            bytes: 0,
+            length,
+            ended: false,
+            entry: Some(Entry {
+                log: self,
+                exchange,
+                status,
+                head_time,
+                body_span: tracing::info_span!(parent: &span, "response.body", bytes = tracing::field::Empty),
+                span,
+            }),
+        })
+    }
+}
+
+struct Entry {
+    log: AccessLog,
+    exchange: Exchange,
+    status: StatusCode,
+    head_time: Duration,
+    span: Span,
+    body_span: Span,
+}
+
+/// A response body that counts the bytes sent and writes the access log entry at its end.
+pub struct LoggedBody {
+    body: Body,
+    bytes: u64,
+    // hyper stops polling once it has sent `Content-Length` bytes.
+    length: Option<u64>,
+    ended: bool,
+    entry: Option<Entry>,
+}
+
+impl LoggedBody {
+    fn finish(&mut self) {
+        let Entry { log, exchange, status, head_time, span, body_span } = match self.entry.take() {
+            Some(entry) => entry,
+            None => return,
+        };
+        let millis = |duration: Duration| duration.as_micros() as f64 / 1000.0;
+        tracing::info!(
+            target: ACCESS_TARGET,
+            client = %log.client,
+            method = %log.method,
+            url = %log.url,
+            upstream = exchange.upstream,
+            status = status.as_u16(),
+            bytes = self.bytes,
+            complete = self.ended || self.body.is_end_stream() || self.length == Some(self.bytes),
+            cache = exchange.cache.map(CacheResult::as_str),
+            duration_ms = millis(log.start.elapsed()),
+            head_ms = millis(head_time),
+            upstream_ms = exchange.upstream_time.map(millis),
+        );
+        body_span.record("bytes", self.bytes);
+        drop((body_span, span));
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        let polled = Pin::new(&mut self.body).poll_data(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => self.bytes += chunk.len() as u64,
+            Poll::Ready(Some(Err(_))) => self.finish(),
+            Poll::Ready(None) => {
+                self.ended = true;
+                self.finish();
+            },
+            Poll::Pending => {},
+        }
+        polled
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap<HeaderValue>>, hyper::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use futures::stream;
+    use serde_json::Value;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        // The fields of every access log entry written so far.
+        fn entries(&self) -> Vec<Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<Value>(line).unwrap())
+                .filter(|event| event["target"] == ACCESS_TARGET)
+                .map(|event| event["fields"].clone())
+                .collect()
+        }
+    }
+
+    fn capture_logs() -> (Captured, tracing::subscriber::DefaultGuard) {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry().with(fmt::layer().json().with_writer(move || writer.clone()));
+        (captured, tracing::subscriber::set_default(subscriber))
+    }
+
+    async fn respond(body: Body, length: Option<usize>) -> Response<LoggedBody> {
+        let req = Request::get("http://proxy.example/a?b=1").body(Body::empty()).unwrap();
+        let log = AccessLog::new("192.0.2.1:4000".parse().unwrap(), &req);
+        let (response, exchange) = capture(async {
+            record_upstream("https://upstream.example");
+            record_cache_result(CacheResult::Miss);
+            record_upstream_time(Duration::from_millis(10));
+            record_upstream_time(Duration::from_millis(5));
+            let mut response = Response::builder().status(StatusCode::CREATED);
+            if let Some(length) = length {
+                response = response.header(header::CONTENT_LENGTH, length);
+            }
+            response.body(body).unwrap()
+        })
+        .await;
+        log.attach(response, exchange, Span::none())
+    }
+
+    #[tokio::test]
+    async fn logs_each_exchange_once_its_body_is_sent() {
+        let (captured, _guard) = capture_logs();
+        let response = respond(Body::from("hello"), Some(5)).await;
+        assert!(captured.entries().is_empty());
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "hello");
+
+        let entries = captured.entries();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(entry["client"], "192.0.2.1:4000");
+        assert_eq!(entry["method"], "GET");
+        assert_eq!(entry["url"], "http://proxy.example/a?b=1");
+        assert_eq!(entry["upstream"], "https://upstream.example");
+        assert_eq!(entry["status"], 201);
+        assert_eq!(entry["bytes"], 5);
+        assert_eq!(entry["complete"], true);
+        assert_eq!(entry["cache"], "miss");
+        assert_eq!(entry["upstream_ms"], 15.0);
+        assert!(entry["duration_ms"].as_f64().unwrap() >= entry["head_ms"].as_f64().unwrap());
+    }
+
+    #[tokio::test]
+    async fn logs_bodies_dropped_before_their_end_as_incomplete() {
+        let (captured, _guard) = capture_logs();
+        let chunks: Vec<Result<_, io::Error>> = vec![Ok("abc"), Ok("def")];
+        let mut body = respond(Body::wrap_stream(stream::iter(chunks)), None).await.into_body();
+        assert_eq!(body.data().await.unwrap().unwrap(), "abc");
+        drop(body);
+
+        let entries = captured.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((&entries[0]["bytes"], &entries[0]["complete"]), (&Value::from(3), &Value::from(false)));
+    }
+}
@@ -1,163 +1,159 @@
 #![deny(warnings)]
 
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
     convert::Infallible,
-    hash::{Hash, Hasher},
+    future::Future,
+    io,
     sync::Arc,
-    time::{Duration, SystemTime},
 };
 
+use clap::Parser;
+use futures::future;
 use hyper::{
-    body::Bytes,
-    client::HttpConnector,
-    http::HeaderValue,
-    service::{make_service_fn, service_fn},
-    {Body, Error, Request, Response, Server, Client, StatusCode, Version, HeaderMap},
+    server::conn::{AddrIncoming, AddrStream},
+    service::{make_service_fn, service_fn, Service},
+    Body, Request, Response, Server,
+};
+use hyper_rustls::{acceptor::TlsStream, TlsAcceptor};
+
+use proxy_with_cache::{
+    admin::Admin,
+    config::{Cli, Config},
+    controller::Controller,
+    disk::DiskCache,
+    forwarding::Peer,
+    logging::{self, LoggedBody},
+    metrics::Metrics,
+    store::{CacheStore, DiskStore, MemoryStore, TieredStore},
+    tls,
 };
-use hyper_tls::HttpsConnector;
-use tokio::sync::Mutex;
-
-
-const TTL: Duration = Duration::new(30, 0);
-
-
-struct CachedResponse {
-    status: StatusCode,
-    version: Version,
-    headers: HeaderMap<HeaderValue>,
-    body: Bytes,
-    expiry: SystemTime,
-}
-
-struct Controller {
-    client: Client<HttpsConnector<HttpConnector>>,
-    cache: Mutex<HashMap<u64, CachedResponse>>,
-}
 
-impl Controller {
-    fn new() -> Controller {
-        let https = HttpsConnector::new();
 
-        Controller {
-            client: Client::builder().build::<_, hyper::Body>(https),
-            cache: Mutex::new(HashMap::new()),
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config = match Config::load(Cli::parse()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(2);
         }
-    }
-
-    pub async fn process(&self, req: Request<Body>) -> Result<Response<Body>, Error> {
-        let req_hash = self.calculate_hash(&format!("{:?}", req));
+    };
+    let _log_guard = match logging::init(&config) {
+        Ok(guard) => guard,
+        Err(error) => {
+            eprintln!("can't set up logging: {}", error);
+            std::process::exit(2);
+        }
+    };
 
-        let response = {
-            let cache = self.cache.lock().await;
-            match cache.get(&req_hash) {
-                Some(cached_response) => {
-                    if cached_response.expiry > SystemTime::now() {
-                        let mut response = Response::builder()
-                            .status(cached_response.status)
-                            .version(cached_response.version)
-                            .body(Body::from(cached_response.body.clone()))
-                            .unwrap();
-                        *response.headers_mut() = cached_response.headers.clone();
-                        Some(response)
-                    } else {
-                        None
-                    }
-                },
-                None => None
+    let memory = Box::new(MemoryStore::new(
+        config.cache.shards,
+        config.cache.max_entries,
+        config.cache.max_bytes,
+        config.cache.eviction_policy,
+    ));
+    let store: Arc<dyn CacheStore> = match &config.cache.disk {
+        Some(disk) => {
+            let policy = disk.eviction_policy.build(disk.max_entries);
+            match DiskCache::open(&disk.path, disk.max_entries, disk.max_bytes, policy) {
+                Ok(cache) => Arc::new(TieredStore::new(memory, Box::new(DiskStore::new(cache)))),
+                Err(error) => {
+                    tracing::error!(path = %disk.path.display(), %error, "can't open the disk cache");
+                    std::process::exit(2);
+                }
             }
-        };
-
-        let response = match response {
-            Some(response) => response,
-            None => {
-                let response = self.proxy(req).await?;
-                println!("{:?}", response);
-                let (parts, body) = response.into_parts();
-                let body = hyper::body::to_bytes(body).await?;
-                let cached = CachedResponse {
-                    status: parts.status.clone(),
-                    version: parts.version.clone(),
-                    headers: parts.headers.clone(),
-                    body: body.clone(),
-                    expiry: SystemTime::now() + TTL,
-                };
-                self.cache.lock().await.insert(req_hash, cached);
-                Response::from_parts(parts, Body::from(body))
+        },
+        None => Arc::from(memory as Box<dyn CacheStore>),
+    };
+    let metrics = Arc::new(Metrics::default());
+    let controller = Arc::new(Controller::new(
+        &config,
+        store.clone(),
+        Box::new(config.cache.key.clone()),
+        metrics.clone(),
+    ));
+
+    let tls_config = match &config.tls {
+        Some(tls_config) => match tls::server_config(tls_config) {
+            Ok(server_config) => Some(server_config),
+            Err(error) => {
+                tracing::error!(%error, "can't load the TLS certificate");
+                std::process::exit(2);
             }
-        };
-        Ok(response)
-    }
+        },
+        None => None,
+    };
 
-    async fn proxy(&self, mut req: Request<Body>) -> Result<Response<Body>, Error> {
-        match req.headers_mut().remove("Origin") {
-            Some(origin_address) => {
-                let uri_string = format!(
-                    "{}{}",
-                    origin_address.to_str().unwrap(),
-                    req.uri()
-                        .path_and_query()
-                        .map(|x| x.as_str())
-                        .unwrap_or("/")
-                );
-                let uri = uri_string.parse().unwrap();
-                *req.uri_mut() = uri;
-            
-                let res = self.client.request(req).await?;
-                Ok(res)
+    // hyper tells HTTP/2 from HTTP/1 by the connection preface, which covers h2c with prior
+    // knowledge as well as ALPN-negotiated h2.
+    let mut servers = Vec::new();
+    for addr in &config.listen {
+        let incoming = AddrIncoming::bind(addr)?;
+        let server = match &tls_config {
+            Some(server_config) => {
+                let acceptor = TlsAcceptor::builder()
+                    .with_tls_config(server_config.clone())
+                    .with_all_versions_alpn()
+                    .with_incoming(incoming);
+                let controller = controller.clone();
+                let make_svc = make_service_fn(move |conn: &TlsStream| {
+                    let peer = conn.io().map(|io| Peer { addr: io.remote_addr(), tls: true });
+                    let controller = controller.clone();
+                    async move {
+                        peer.map(|peer| proxy_service(controller, peer))
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+                    }
+                });
+                tracing::info!("listening on https://{}", addr);
+                future::Either::Left(Server::builder(acceptor).serve(make_svc))
             },
             None => {
-                let mut bad_request = Response::default();
-                *bad_request.status_mut() = StatusCode::BAD_REQUEST;
-                Ok(bad_request)
-            }
-        }
-    }
-
-    fn calculate_hash<T: Hash>(&self, t: &T) -> u64 {
-        let mut s = DefaultHasher::new();
-        t.hash(&mut s);
-        s.finish()
-    }
-
-    pub async fn clear_expired_cache(&self) -> Result<(), Error> {
-        loop {
-            let now = SystemTime::now();
-            self.cache.lock().await.retain(|_, cached| cached.expiry > now);
-            tokio::time::sleep(Duration::new(1, 0)).await;
-        }
+                let controller = controller.clone();
+                let make_svc = make_service_fn(move |conn: &AddrStream| {
+                    let peer = Peer { addr: conn.remote_addr(), tls: false };
+                    let controller = controller.clone();
+                    async move { Ok::<_, Infallible>(proxy_service(controller, peer)) }
+                });
+                tracing::info!("listening on http://{}", addr);
+                future::Either::Right(Server::builder(incoming).serve(make_svc))
+            },
+        };
+        servers.push(server);
     }
-}
 
-#[tokio::main]
-pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = ([127, 0, 0, 1], 3000).into();
-
-    let controller = Arc::new(Controller::new());
-
-    let make_svc = {
-        let controller = controller.clone();
-        make_service_fn(move |_| {
-            let controller = controller.clone();
-            async move {
-                Ok::<_, Infallible>(service_fn(move |req| {
-                    let controller = controller.clone();
-                    async move {
-                        controller.process(req).await
-                    }
-                }))
-            }
-        })
+    let admin = match &config.admin {
+        Some(admin_config) => {
+            let admin = Arc::new(Admin::new(store, metrics, admin_config.token.clone()));
+            let make_svc = make_service_fn(move |_| {
+                let admin = admin.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| admin.clone().process(req)))
+                }
+            });
+            let server = Server::try_bind(&admin_config.listen)?.serve(make_svc);
+            tracing::info!("admin API on http://{}", admin_config.listen);
+            future::Either::Left(server)
+        },
+        None => future::Either::Right(future::pending()),
     };
 
-    let server = Server::bind(&addr).serve(make_svc);
-
-    println!("Listening on http://{}", addr);
-
-    match tokio::join!(
-        server,
+    let _ = tokio::join!(
+        future::try_join_all(servers),
+        admin,
         controller.clear_expired_cache()
-    ) {
-        _ => Ok(())
-    }
-}
\ No newline at end of file
+    );
+    Ok(())
+}
+
+// Requests on one connection all come from the same peer.
+fn proxy_service(
+    controller: Arc<Controller>,
+    peer: Peer,
+) -> impl Service<
+    Request<Body>,
+    Response = Response<LoggedBody>,
+    Error = Infallible,
+    Future = impl Future<Output = Result<Response<LoggedBody>, Infallible>> + Send,
+> + Send {
+    service_fn(move |req| controller.clone().process(req, peer))
+}
@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+use hyper::StatusCode;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::cache::CacheStats;
+
+
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+
+/// How the cache took part in answering a request.
+#[derive(Debug, Clone, Copy)]
+pub enum CacheResult {
+    /// Served fresh from the cache.
+    Hit,
+    /// Served from the cache while stale.
+    Stale,
+    /// A stored response was confirmed by a 304 from the upstream.
+    Revalidated,
+    /// Fetched from the upstream, alone or as part of a coalesced flight.
+    Miss,
+    /// Never looked up, e.g. an unsafe method.
+    Bypass,
+}
+
+impl CacheResult {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheResult::Hit => "hit",
+            CacheResult::Stale => "stale",
+            CacheResult::Revalidated => "revalidated",
+            CacheResult::Miss => "miss",
+            CacheResult::Bypass => "bypass",
+        }
+    }
+}
+
+/// Counters and histograms exposed in the Prometheus text format. Latencies are measured
+/// until the response head is available, not until the body has been sent.
+pub struct Metrics {
+    registry: Registry,
+    cache_requests: IntCounterVec,
+    requests: HistogramVec,
+    upstream_requests: HistogramVec,
+    cache_entries: IntGaugeVec,
+    cache_bytes: IntGaugeVec,
+    cache_evictions: IntCounterVec,
+    cache_rejections: IntCounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        let cache_requests = IntCounterVec::new(
+            Opts::new("proxy_cache_requests_total", "Requests by how the cache answered them"),
+            &["result"],
+        ).unwrap();
+        let requests = HistogramVec::new(
+            HistogramOpts::new("proxy_request_duration_seconds", "Client request latency by response status")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            &["status"],
+        ).unwrap();
+        let upstream_requests = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_upstream_request_duration_seconds",
+                "Upstream request latency by host and response status, `error` if none was received",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["host", "status"],
+        ).unwrap();
+        let per_tier = |name: &str, help: &str| IntGaugeVec::new(Opts::new(name, help), &["tier"]).unwrap();
+        let cache_entries = per_tier("proxy_cache_entries", "Stored keys");
+        let cache_bytes = per_tier("proxy_cache_bytes", "Stored body bytes");
+        let per_tier = |name: &str, help: &str| IntCounterVec::new(Opts::new(name, help), &["tier"]).unwrap();
+        let cache_evictions = per_tier("proxy_cache_evictions_total", "Keys evicted to make room");
+        let cache_rejections = per_tier("proxy_cache_rejections_total", "Responses refused by the size limit or admission policy");
+
+        let registry = Registry::new();
+        registry.register(Box::new(cache_requests.clone())).unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(upstream_requests.clone())).unwrap();
+        registry.register(Box::new(cache_entries.clone())).unwrap();
+        registry.register(Box::new(cache_bytes.clone())).unwrap();
+        registry.register(Box::new(cache_evictions.clone())).unwrap();
+        registry.register(Box::new(cache_rejections.clone())).unwrap();
+        Metrics {
+            registry,
+            cache_requests,
+            requests,
+            upstream_requests,
+            cache_entries,
+            cache_bytes,
+            cache_evictions,
+            cache_rejections,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn cache_result(&self, result: CacheResult) {
+        self.cache_requests.with_label_values(&[result.as_str()]).inc();
+    }
+
+    pub fn request(&self, status: StatusCode, duration: Duration) {
+        self.requests.with_label_values(&[status.as_str()]).observe(duration.as_secs_f64());
+    }
+
+    pub fn upstream_request(&self, host: &str, status: Option<StatusCode>, duration: Duration) {
+        let status = status.as_ref().map_or("error", StatusCode::as_str);
+        self.upstream_requests.with_label_values(&[host, status]).observe(duration.as_secs_f64());
+    }
+
+    /// The metrics in the text exposition format, with the cache sizes set to `stats`.
+    pub fn render(&self, stats: &[(&'static str, CacheStats)]) -> String {
+        for (tier, stats) in stats {
+            self.cache_entries.with_label_values(&[tier]).set(stats.entries as i64);
+            self.cache_bytes.with_label_values(&[tier]).set(stats.bytes as i64);
+            // The stores count these themselves, the counters only catch up.
+            let evictions = self.cache_evictions.with_label_values(&[tier]);
+            evictions.inc_by(stats.evictions.saturating_sub(evictions.get()));
+            let rejections = self.cache_rejections.with_label_values(&[tier]);
+            rejections.inc_by(stats.rejections.saturating_sub(rejections.get()));
+        }
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(entries: usize, bytes: usize, evictions: u64, rejections: u64) -> CacheStats {
+        CacheStats { entries, bytes, evictions, rejections }
+    }
+
+    // The lines of `rendered` holding samples of `metric`.
+    fn samples<'a>(rendered: &'a str, metric: &str) -> Vec<&'a str> {
+        rendered.lines().filter(|line| line.starts_with(metric) && !line.starts_with('#')).collect()
+    }
+
+    #[test]
+    fn renders_requests_and_upstream_latencies() {
+        let metrics = Metrics::default();
+        metrics.cache_result(CacheResult::Hit);
+        metrics.cache_result(CacheResult::Hit);
+        metrics.cache_result(CacheResult::Miss);
+        metrics.request(StatusCode::OK, Duration::from_millis(3));
+        metrics.upstream_request("example.com", None, Duration::from_secs(1));
+        let rendered = metrics.render(&[]);
+
+        assert!(rendered.contains("# TYPE proxy_cache_requests_total counter"));
+        assert_eq!(
+            samples(&rendered, "proxy_cache_requests_total"),
+            ["proxy_cache_requests_total{result=\"hit\"} 2", "proxy_cache_requests_total{result=\"miss\"} 1"]
+        );
+        assert!(rendered.contains("proxy_request_duration_seconds_bucket{status=\"200\",le=\"0.0025\"} 0"));
+        assert!(rendered.contains("proxy_request_duration_seconds_bucket{status=\"200\",le=\"0.005\"} 1"));
+        assert_eq!(
+            samples(&rendered, "proxy_upstream_request_duration_seconds_count"),
+            ["proxy_upstream_request_duration_seconds_count{host=\"example.com\",status=\"error\"} 1"]
+        );
+    }
+
+    #[test]
+    fn cache_counters_catch_up_with_the_stores() {
+        let metrics = Metrics::default();
+        metrics.render(&[("memory", stats(3, 300, 2, 1)), ("disk", stats(1, 100, 0, 0))]);
+        let rendered = metrics.render(&[("memory", stats(2, 200, 5, 1)), ("disk", stats(1, 100, 0, 0))]);
+
+        assert_eq!(
+            samples(&rendered, "proxy_cache_entries"),
+            ["proxy_cache_entries{tier=\"disk\"} 1", "proxy_cache_entries{tier=\"memory\"} 2"]
+        );
+        assert!(rendered.contains("proxy_cache_bytes{tier=\"memory\"} 200"));
+        // Counters are only ever raised to the stores' own totals.
+        assert!(rendered.contains("proxy_cache_evictions_total{tier=\"memory\"} 5"));
+        assert!(rendered.contains("proxy_cache_rejections_total{tier=\"memory\"} 1"));
+        assert!(rendered.contains("proxy_cache_evictions_total{tier=\"disk\"} 0"));
+    }
+}
@@ -0,0 +1,387 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::{stream, StreamExt};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header,
+    http::HeaderValue,
+    Body, HeaderMap, Response, StatusCode,
+};
+
+
+// More ranges than this are answered with the full body rather than a huge multipart response.
+const MAX_RANGES: usize = 32;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeSpec {
+    FromTo(u64, Option<u64>),
+    Suffix(u64),
+}
+
+/// A client's `Range` and `If-Range` headers, applied to the full response after it was
+/// served from the cache or fetched from the upstream.
+#[derive(Debug)]
+pub struct RangeRequest {
+    ranges: Vec<RangeSpec>,
+    if_range: Option<HeaderValue>,
+}
+
+impl RangeRequest {
+    /// Removes `Range` and `If-Range` from the request headers. Ranges in units other than
+    /// bytes or with invalid syntax are ignored, as RFC 9110 section 14.2 allows.
+    pub fn take(headers: &mut HeaderMap<HeaderValue>) -> Option<RangeRequest> {
+        let range = headers.remove(header::RANGE);
+        let if_range = headers.remove(header::IF_RANGE);
+        let ranges = parse(range?.to_str().ok()?)?;
+        Some(RangeRequest { ranges, if_range })
+    }
+
+    pub async fn apply(self, response: Response<Body>, max_body_size: usize) -> Response<Body> {
+        if response.status() != StatusCode::OK || !self.if_range_matches(response.headers()) {
+            return response;
+        }
+        let (mut parts, body) = response.into_parts();
+        let (body, length) = match buffer(body, &parts.headers, max_body_size).await {
+            Ok(body) => {
+                let length = body.len() as u64;
+                (Ok(body), length)
+            },
+            // Too large to buffer, but a single range can still be cut out of the body as it
+            // streams past when its length is known up front.
+            Err(body) => match content_length(&parts.headers) {
+                Some(length) => (Err(body), length),
+                None => return Response::from_parts(parts, body),
+            },
+        };
+        let ranges = match self.resolve(length) {
+            Some(ranges) if body.is_ok() || ranges.len() <= 1 => ranges,
+            _ => return Response::from_parts(parts, body.map(Body::from).unwrap_or_else(|body| body)),
+        };
+        parts.headers.remove(header::TRANSFER_ENCODING);
+        parts.headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+        if ranges.is_empty() {
+            parts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            parts.headers.remove(header::CONTENT_TYPE);
+            parts.headers.insert(header::CONTENT_RANGE, header_value(format!("bytes */{}", length)));
+            parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(0));
+            return Response::from_parts(parts, Body::empty());
+        }
+
+        parts.status = StatusCode::PARTIAL_CONTENT;
+        let body = match body {
+            Ok(body) => body,
+            Err(body) => {
+                let (start, end) = ranges[0];
+                parts.headers.insert(header::CONTENT_RANGE, content_range(start, end, length));
+                parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start + 1));
+                return Response::from_parts(parts, slice(body, start, end - start + 1));
+            },
+        };
+        let body = if let [(start, end)] = ranges[..] {
+            parts.headers.insert(header::CONTENT_RANGE, content_range(start, end, length));
+            body.slice(start as usize..=end as usize)
+        } else {
+            let boundary = boundary();
+            let content_type = parts.headers.remove(header::CONTENT_TYPE);
+            let mut multipart = Vec::new();
+            for (start, end) in ranges {
+                multipart.extend_from_slice(format!("\r\n--{}\r\n", boundary).as_bytes());
+                if let Some(content_type) = &content_type {
+                    multipart.extend_from_slice(b"Content-Type: ");
+                    multipart.extend_from_slice(content_type.as_bytes());
+                    multipart.extend_from_slice(b"\r\n");
+                }
+                multipart.extend_from_slice(format!("Content-Range: bytes {}-{}/{}\r\n\r\n", start, end, length).as_bytes());
+                multipart.extend_from_slice(&body[start as usize..=end as usize]);
+            }
+            multipart.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+            parts.headers.insert(
+                header::CONTENT_TYPE,
+                header_value(format!("multipart/byteranges; boundary={}", boundary)),
+            );
+            Bytes::from(multipart)
+        };
+        parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+        Response::from_parts(parts, Body::from(body))
+    }
+
+    // RFC 9110 section 13.1.5: ranges only apply to the representation the client already has.
+    fn if_range_matches(&self, headers: &HeaderMap<HeaderValue>) -> bool {
+        let if_range = match self.if_range.as_ref().and_then(|value| value.to_str().ok()) {
+            Some(if_range) => if_range.trim(),
+            None => return self.if_range.is_none(),
+        };
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            // Only a strong comparison counts.
+            let etag = headers.get(header::ETAG).and_then(|etag| etag.to_str().ok());
+            return !if_range.starts_with("W/") && etag.is_some_and(|etag| etag.trim() == if_range);
+        }
+        let date = |value: &str| httpdate::parse_http_date(value).ok();
+        let last_modified = headers.get(header::LAST_MODIFIED).and_then(|value| value.to_str().ok());
+        matches!((date(if_range), last_modified.and_then(date)), (Some(a), Some(b)) if a == b)
+    }
+
+    /// Inclusive byte offsets into a body of `length` bytes, in ascending order with
+    /// overlapping and adjacent ranges coalesced (RFC 9110 section 14.6), so that the parts
+    /// never add up to more than the body. Empty when none of the ranges is satisfiable,
+    /// `None` when the full body should be sent instead.
+    fn resolve(&self, length: u64) -> Option<Vec<(u64, u64)>> {
+        if self.ranges.len() > MAX_RANGES {
+            return None;
+        }
+        let mut ranges: Vec<(u64, u64)> = self.ranges
+            .iter()
+            .filter_map(|range| match *range {
+                RangeSpec::FromTo(start, _) if start >= length => None,
+                RangeSpec::FromTo(start, end) => Some((start, end.map_or(length - 1, |end| end.min(length - 1)))),
+                RangeSpec::Suffix(0) => None,
+                RangeSpec::Suffix(suffix) if length > 0 => Some((length.saturating_sub(suffix), length - 1)),
+                RangeSpec::Suffix(_) => None,
+            })
+            .collect();
+        ranges.sort_unstable();
+        let mut coalesced: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match coalesced.last_mut() {
+                Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+                _ => coalesced.push((start, end)),
+            }
+        }
+        Some(coalesced)
+    }
+}
+
+fn parse(value: &str) -> Option<Vec<RangeSpec>> {
+    let (unit, ranges) = value.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let mut specs = Vec::new();
+    for range in ranges.split(',').map(str::trim).filter(|range| !range.is_empty()) {
+        let (start, end) = range.split_once('-')?;
+        let number = |value: &str| -> Option<u64> {
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            value.parse().ok()
+        };
+        let spec = match (start.trim(), end.trim()) {
+            ("", suffix) => RangeSpec::Suffix(number(suffix)?),
+            (start, "") => RangeSpec::FromTo(number(start)?, None),
+            (start, end) => {
+                let (start, end) = (number(start)?, number(end)?);
+                if start > end {
+                    return None;
+                }
+                RangeSpec::FromTo(start, Some(end))
+            },
+        };
+        specs.push(spec);
+    }
+    if specs.is_empty() {
+        None
+    } else {
+        Some(specs)
+    }
+}
+
+/// Collects a body of at most `limit` bytes. A larger one is handed back with the chunks
+/// read so far put in front again.
+async fn buffer(mut body: Body, headers: &HeaderMap<HeaderValue>, limit: usize) -> Result<Bytes, Body> {
+    if content_length(headers).is_some_and(|length| length > limit as u64) {
+        return Err(body);
+    }
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    while let Some(chunk) = body.data().await {
+        let failed = chunk.is_err();
+        size += chunk.as_ref().map_or(0, Bytes::len);
+        chunks.push(chunk);
+        if failed || size > limit {
+            return Err(Body::wrap_stream(stream::iter(chunks).chain(body)));
+        }
+    }
+    let mut bytes = Vec::with_capacity(size);
+    for chunk in chunks.into_iter().flatten() {
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(bytes))
+}
+
+/// The `length` bytes of `body` from offset `start`, read as they arrive. The rest of the body
+/// is skipped or, once the range is complete, not read at all.
+fn slice(body: Body, start: u64, length: u64) -> Body {
+    let chunks = stream::unfold((body, start, length), |(mut body, mut skip, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        loop {
+            let chunk = match body.data().await? {
+                Ok(chunk) => chunk,
+                Err(error) => return Some((Err(error), (body, skip, 0))),
+            };
+            let skipped = skip.min(chunk.len() as u64);
+            skip -= skipped;
+            let taken = remaining.min(chunk.len() as u64 - skipped);
+            if taken > 0 {
+                let chunk = chunk.slice(skipped as usize..(skipped + taken) as usize);
+                return Some((Ok(chunk), (body, skip, remaining - taken)));
+            }
+        }
+    });
+    Body::wrap_stream(chunks)
+}
+
+fn content_length(headers: &HeaderMap<HeaderValue>) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+fn content_range(start: u64, end: u64, length: u64) -> HeaderValue {
+    header_value(format!("bytes {}-{}/{}", start, end, length))
+}
+
+fn header_value(value: String) -> HeaderValue {
+    HeaderValue::try_from(value).unwrap()
+}
+
+fn boundary() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    format!("proxy-with-cache-{:x}", nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(range: &str, if_range: Option<&str>) -> Option<RangeRequest> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_str(range).unwrap());
+        if let Some(if_range) = if_range {
+            headers.insert(header::IF_RANGE, HeaderValue::from_str(if_range).unwrap());
+        }
+        let request = RangeRequest::take(&mut headers);
+        assert!(headers.is_empty());
+        request
+    }
+
+    fn response(body: &'static [u8], content_length: bool) -> Response<Body> {
+        let mut response = Response::new(Body::from(body));
+        if content_length {
+            response.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+        }
+        response
+    }
+
+    async fn body(response: Response<Body>) -> Bytes {
+        hyper::body::to_bytes(response.into_body()).await.unwrap()
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(
+            parse("bytes=0-99, 200-, -50"),
+            Some(vec![RangeSpec::FromTo(0, Some(99)), RangeSpec::FromTo(200, None), RangeSpec::Suffix(50)])
+        );
+        assert_eq!(parse("Bytes = 5-5,"), Some(vec![RangeSpec::FromTo(5, Some(5))]));
+        for invalid in ["items=0-1", "bytes=", "bytes=5-1", "bytes=a-b", "bytes=-", "bytes=+1-2", "0-1"] {
+            assert_eq!(parse(invalid), None, "{}", invalid);
+        }
+        assert!(request("bytes=1-2", None).is_some());
+        assert!(request("lines=1-2", Some("\"v1\"")).is_none());
+    }
+
+    #[test]
+    fn resolves_against_the_length() {
+        let resolve = |range: &str, length| request(range, None).unwrap().resolve(length);
+        assert_eq!(resolve("bytes=0-99", 50), Some(vec![(0, 49)]));
+        assert_eq!(resolve("bytes=10-", 50), Some(vec![(10, 49)]));
+        assert_eq!(resolve("bytes=-10", 50), Some(vec![(40, 49)]));
+        assert_eq!(resolve("bytes=-100", 50), Some(vec![(0, 49)]));
+        assert_eq!(resolve("bytes=0-0,-1", 50), Some(vec![(0, 0), (49, 49)]));
+        assert_eq!(resolve("bytes=-1,0-0", 50), Some(vec![(0, 0), (49, 49)]));
+        // Unsatisfiable ranges are dropped, leaving none for a 416.
+        assert_eq!(resolve("bytes=50-,-0", 50), Some(vec![]));
+        assert_eq!(resolve("bytes=-5", 0), Some(vec![]));
+        let many = (0..=MAX_RANGES).map(|i| format!("{}-{}", i, i)).collect::<Vec<_>>().join(",");
+        assert_eq!(resolve(&format!("bytes={}", many), 100), None);
+    }
+
+    #[test]
+    fn coalesces_overlapping_and_adjacent_ranges() {
+        let resolve = |range: &str, length| request(range, None).unwrap().resolve(length);
+        let repeated = vec!["0-"; MAX_RANGES].join(",");
+        assert_eq!(resolve(&format!("bytes={}", repeated), 50), Some(vec![(0, 49)]));
+        assert_eq!(resolve("bytes=20-29,0-9,5-19,40-,-5", 50), Some(vec![(0, 29), (40, 49)]));
+        assert_eq!(resolve("bytes=0-9,11-19", 50), Some(vec![(0, 9), (11, 19)]));
+    }
+
+    #[tokio::test]
+    async fn repeated_ranges_are_served_once() {
+        let repeated = vec!["0-"; MAX_RANGES].join(",");
+        let request = request(&format!("bytes={}", repeated), None).unwrap();
+        let response = request.apply(response(b"0123456789", false), 100).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-9/10");
+        assert_eq!(body(response).await, "0123456789");
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_etag_or_exact_date() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, HeaderValue::from_static("\"v1\""));
+        headers.insert(header::LAST_MODIFIED, HeaderValue::from_static("Thu, 01 Jan 2026 00:00:00 GMT"));
+        let matches = |if_range: Option<&str>| request("bytes=0-1", if_range).unwrap().if_range_matches(&headers);
+        assert!(matches(None));
+        assert!(matches(Some("\"v1\"")));
+        assert!(!matches(Some("\"v2\"")));
+        assert!(!matches(Some("W/\"v1\"")));
+        assert!(matches(Some("Thu, 01 Jan 2026 00:00:00 GMT")));
+        assert!(!matches(Some("Thu, 01 Jan 2026 00:00:01 GMT")));
+        assert!(!matches(Some("yesterday")));
+    }
+
+    #[tokio::test]
+    async fn applies_single_and_multiple_ranges() {
+        let partial = request("bytes=2-5", None).unwrap().apply(response(b"0123456789", false), 100).await;
+        assert_eq!(partial.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(partial.headers()[header::CONTENT_RANGE], "bytes 2-5/10");
+        assert_eq!(body(partial).await, "2345");
+
+        let multipart = request("bytes=0-0,-1", None).unwrap().apply(response(b"0123456789", false), 100).await;
+        assert!(multipart.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("multipart/byteranges"));
+        let multipart = body(multipart).await;
+        let multipart = String::from_utf8_lossy(&multipart);
+        assert!(multipart.contains("Content-Range: bytes 0-0/10\r\n\r\n0"));
+        assert!(multipart.contains("Content-Range: bytes 9-9/10\r\n\r\n9"));
+
+        let unsatisfiable = request("bytes=10-", None).unwrap().apply(response(b"0123456789", false), 100).await;
+        assert_eq!(unsatisfiable.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(unsatisfiable.headers()[header::CONTENT_RANGE], "bytes */10");
+    }
+
+    #[tokio::test]
+    async fn streams_a_single_range_of_a_body_too_large_to_buffer() {
+        let partial = request("bytes=3-7", None).unwrap().apply(response(b"0123456789", true), 4).await;
+        assert_eq!(partial.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(partial.headers()[header::CONTENT_RANGE], "bytes 3-7/10");
+        assert_eq!(partial.headers()[header::CONTENT_LENGTH], "5");
+        assert_eq!(body(partial).await, "34567");
+
+        // Chunks are skipped and cut wherever the range falls.
+        let chunks = ["012", "345", "678", "9"].map(|chunk| Ok::<_, hyper::Error>(Bytes::from(chunk)));
+        assert_eq!(hyper::body::to_bytes(slice(Body::wrap_stream(stream::iter(chunks)), 4, 4)).await.unwrap(), "4567");
+
+        // Several ranges, or no known length, get the full body.
+        let full = request("bytes=0-0,-1", None).unwrap().apply(response(b"0123456789", true), 4).await;
+        assert_eq!(full.status(), StatusCode::OK);
+        assert_eq!(body(full).await, "0123456789");
+        let full = request("bytes=3-7", None).unwrap().apply(response(b"0123456789", false), 4).await;
+        assert_eq!(full.status(), StatusCode::OK);
+        assert_eq!(body(full).await, "0123456789");
+    }
+}
@@ -0,0 +1,266 @@
+use hyper::{
+    header,
+    http::request,
+    Uri,
+};
+use serde::Deserialize;
+
+use crate::{
+    access::OriginPattern,
+    cache_key::{self, DefaultKeyBuilder},
+    error::ProxyError,
+};
+
+
+/// Maps a path prefix and/or Host header to a named upstream.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    pub name: String,
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// `scheme://authority` with an optional base path that forwarded paths are appended to.
+    pub upstream: String,
+    /// Remove the matched prefix before forwarding.
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// How requests on this route are keyed, instead of `[cache.key]`.
+    #[serde(default)]
+    pub key: Option<DefaultKeyBuilder>,
+}
+
+impl Route {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("name must not be empty".to_owned());
+        }
+        if self.host.is_none() && self.prefix.is_none() {
+            return Err(format!("route `{}` needs a host or a prefix", self.name));
+        }
+        if let Some(prefix) = &self.prefix {
+            if !prefix.starts_with('/') {
+                return Err(format!("route `{}` prefix must start with `/`", self.name));
+            }
+        }
+        match self.upstream.parse::<Uri>() {
+            Ok(uri) if matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some() => {
+                if uri.query().is_some() {
+                    return Err(format!("route `{}` upstream must not have a query", self.name));
+                }
+                Ok(())
+            },
+            _ => Err(format!("route `{}` upstream `{}` is not an http(s) URL", self.name, self.upstream)),
+        }
+    }
+
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match (&self.host, host) {
+            (Some(route_host), Some(host)) => route_host.eq_ignore_ascii_case(host),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        host_matches && self.prefix.as_ref().is_none_or(|prefix| has_prefix(path, prefix))
+    }
+
+    // Routes bound to a host beat host-less ones, then the longest prefix wins.
+    fn specificity(&self) -> (bool, usize) {
+        (self.host.is_some(), self.prefix.as_ref().map_or(0, String::len))
+    }
+
+    fn target(&self, path_and_query: &str) -> Result<Target, ProxyError> {
+        let upstream: Uri = self.upstream.parse().map_err(|_| ProxyError::BadTarget(self.upstream.clone()))?;
+        let path_and_query = match &self.prefix {
+            Some(prefix) if self.strip_prefix => &path_and_query[prefix.len()..],
+            _ => path_and_query,
+        };
+        let base = upstream.path().trim_end_matches('/');
+        let separator = if path_and_query.starts_with('/') { "" } else { "/" };
+        let origin = format!("{}://{}", upstream.scheme_str().unwrap_or("http"), upstream.authority().unwrap());
+        let uri = format!("{}{}{}{}", origin, base, separator, path_and_query);
+        Ok(Target {
+            uri: uri.parse().map_err(|_| ProxyError::BadTarget(uri))?,
+            origin,
+            route: Some(self.name.clone()),
+        })
+    }
+}
+
+pub struct Target {
+    pub origin: String,
+    pub uri: Uri,
+    /// The name of the matched route, `None` for targets picked with the `Origin` header.
+    pub route: Option<String>,
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    origin_header: bool,
+    allowed_origins: Vec<OriginPattern>,
+}
+
+impl Router {
+    pub fn new(routes: Vec<Route>, origin_header: bool, allowed_origins: Vec<OriginPattern>) -> Router {
+        Router { routes, origin_header, allowed_origins }
+    }
+
+    /// Picks the upstream for a request. In `Origin`-header mode the header is consumed.
+    pub fn route(&self, parts: &mut request::Parts) -> Result<Target, ProxyError> {
+        // Matching on the normalized path keeps `..` segments from leaving a route's prefix or
+        // the upstream's base path.
+        let path = cache_key::normalize_path(parts.uri.path());
+        let path_and_query = match parts.uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.clone(),
+        };
+        let host = parts
+            .headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| parts.uri.host())
+            .map(strip_port);
+
+        let route = self.routes
+            .iter()
+            .filter(|route| route.matches(host, &path))
+            .max_by_key(|route| route.specificity());
+        if let Some(route) = route {
+            return route.target(&path_and_query);
+        }
+
+        if !self.origin_header {
+            return Err(ProxyError::NoRoute);
+        }
+        let origin = match parts.headers.remove(header::ORIGIN) {
+            Some(origin) => origin,
+            None => return Err(ProxyError::NoRoute),
+        };
+        let origin = match origin.to_str() {
+            Ok(origin) => origin.to_owned(),
+            Err(_) => return Err(ProxyError::BadTarget(String::from_utf8_lossy(origin.as_bytes()).into_owned())),
+        };
+        let uri: Uri = match format!("{}{}", origin, path_and_query).parse() {
+            Ok(uri) => uri,
+            Err(_) => return Err(ProxyError::BadTarget(origin)),
+        };
+        let uri_origin = uri.scheme().zip(uri.authority()).map(|(scheme, authority)| format!("{}://{}", scheme, authority));
+        if uri_origin.as_deref() != Some(origin.trim_end_matches('/')) {
+            return Err(ProxyError::BadTarget(origin));
+        }
+        if !self.allowed_origins.iter().any(|pattern| pattern.matches(&uri)) {
+            return Err(ProxyError::Forbidden { origin, reason: "origin is not in the allow-list".to_owned() });
+        }
+        Ok(Target { origin, uri, route: None })
+    }
+}
+
+// A prefix covers whole segments, so `/btc` matches `/btc` and `/btc/tx` but not `/btcfoo`.
+fn has_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'))
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') && port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Request;
+
+    use super::*;
+
+    fn route(name: &str, host: Option<&str>, prefix: Option<&str>, upstream: &str, strip_prefix: bool) -> Route {
+        Route {
+            name: name.to_owned(),
+            host: host.map(str::to_owned),
+            prefix: prefix.map(str::to_owned),
+            upstream: upstream.to_owned(),
+            strip_prefix,
+            key: None,
+        }
+    }
+
+    fn router() -> Router {
+        Router::new(
+            vec![
+                route("btc", None, Some("/btc"), "https://blockstream.info/api", true),
+                route("btc-tx", None, Some("/btc/tx"), "https://tx.example.com", false),
+                route("mempool", Some("mempool.localhost"), None, "https://mempool.space/base/", false),
+            ],
+            true,
+            vec!["https://*.example.com".parse().unwrap()],
+        )
+    }
+
+    fn route_request(router: &Router, request: Request<()>) -> Result<Target, ProxyError> {
+        let (mut parts, _) = request.into_parts();
+        router.route(&mut parts)
+    }
+
+    fn route_path(router: &Router, path: &str) -> Option<(Option<String>, String)> {
+        let target = route_request(router, Request::get(path).body(()).unwrap()).ok()?;
+        Some((target.route, target.uri.to_string()))
+    }
+
+    fn routed(route: &str, uri: &str) -> Option<(Option<String>, String)> {
+        Some((Some(route.to_owned()), uri.to_owned()))
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        let router = router();
+        assert_eq!(route_path(&router, "/btc"), routed("btc", "https://blockstream.info/api/"));
+        assert_eq!(route_path(&router, "/btc/blocks?limit=1"), routed("btc", "https://blockstream.info/api/blocks?limit=1"));
+        assert_eq!(route_path(&router, "/btc?limit=1"), routed("btc", "https://blockstream.info/api/?limit=1"));
+        assert_eq!(route_path(&router, "/btcfoo"), None);
+        // The longest matching prefix wins.
+        assert_eq!(route_path(&router, "/btc/tx/abc"), routed("btc-tx", "https://tx.example.com/btc/tx/abc"));
+        assert_eq!(route_path(&router, "/btc/txs"), routed("btc", "https://blockstream.info/api/txs"));
+    }
+
+    #[test]
+    fn dot_segments_cannot_leave_a_route() {
+        let router = router();
+        assert_eq!(route_path(&router, "/btc/../secret"), None);
+        assert_eq!(route_path(&router, "/btc/tx/../../btc/x"), routed("btc", "https://blockstream.info/api/x"));
+        assert_eq!(route_path(&router, "/btc/%2E%2E/secret"), None);
+        assert_eq!(route_path(&router, "/btc/x/%2e%2e/admin"), routed("btc", "https://blockstream.info/api/admin"));
+    }
+
+    #[test]
+    fn host_routes_beat_prefix_routes() {
+        let router = router();
+        let request = Request::get("/../btc/blocks").header(header::HOST, "Mempool.localhost:3000").body(()).unwrap();
+        let target = route_request(&router, request).ok().unwrap();
+        assert_eq!(target.route.as_deref(), Some("mempool"));
+        assert_eq!(target.uri, "https://mempool.space/base/btc/blocks");
+        assert_eq!(target.origin, "https://mempool.space");
+    }
+
+    #[test]
+    fn picks_allowed_origins_from_the_origin_header() {
+        let router = router();
+        let (mut parts, _) = Request::get("/a/../b?c=1")
+            .header(header::ORIGIN, "https://api.example.com")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let target = router.route(&mut parts).ok().unwrap();
+        assert_eq!((target.route, target.uri.to_string()), (None, "https://api.example.com/b?c=1".to_owned()));
+        assert!(!parts.headers.contains_key(header::ORIGIN));
+
+        let denied = Request::get("/").header(header::ORIGIN, "https://example.org").body(()).unwrap();
+        assert!(matches!(route_request(&router, denied), Err(ProxyError::Forbidden { .. })));
+        let with_path = Request::get("/").header(header::ORIGIN, "https://api.example.com/x").body(()).unwrap();
+        assert!(matches!(route_request(&router, with_path), Err(ProxyError::BadTarget(_))));
+        assert_eq!(route_path(&router, "/nowhere"), None);
+        let (mut parts, _) = Request::get("/").body(()).unwrap().into_parts();
+        let no_origin_header = Router::new(Vec::new(), false, Vec::new());
+        assert!(matches!(no_origin_header.route(&mut parts), Err(ProxyError::NoRoute)));
+    }
+}
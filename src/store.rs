@@ -0,0 +1,317 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io,
+    sync::{Arc, Mutex as StdMutex, MutexGuard},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use hyper::{http::HeaderValue, HeaderMap};
+use tokio::task;
+
+use crate::{
+    cache::{Cache, CacheStats, EntryInfo},
+    cache_key::CacheKey,
+    cached_response::CachedResponse,
+    disk::DiskCache,
+    eviction::PolicyKind,
+};
+
+
+/// Where cached responses live. The controller only talks to this trait, so a store can be
+/// swapped for another backend or a mock without touching request handling.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    /// The stored variant of `key` selected by the request's headers. Failures count as misses.
+    async fn get(&self, key: &CacheKey, request_headers: &HeaderMap<HeaderValue>) -> Option<CachedResponse>;
+    async fn put(&self, key: CacheKey, cached: CachedResponse) -> io::Result<()>;
+    async fn delete(&self, key: &CacheKey) -> io::Result<()>;
+    async fn keys(&self) -> io::Result<Vec<CacheKey>>;
+    async fn entries(&self, now: SystemTime) -> io::Result<Vec<EntryInfo>>;
+    /// Drops entries that can no longer be served or revalidated at `now`.
+    async fn purge(&self, now: SystemTime) -> io::Result<()>;
+    /// Statistics per tier, labelled by tier name.
+    async fn stats(&self) -> Vec<(&'static str, CacheStats)>;
+
+    /// Deletes every method and variant stored for the URL of `target`.
+    async fn invalidate(&self, target: &CacheKey) -> io::Result<()>;
+}
+
+/// In-memory caches split into shards by URL hash, each behind its own short-lived lock,
+/// so concurrent requests rarely contend. All keys of a URL share a shard.
+pub struct MemoryStore {
+    shards: Vec<StdMutex<Cache>>,
+}
+
+impl MemoryStore {
+    /// Spreads the limits evenly over `shards` caches, each with its own eviction policy.
+    pub fn new(shards: usize, max_entries: usize, max_bytes: usize, policy: PolicyKind) -> MemoryStore {
+        let max_entries = max_entries.div_ceil(shards);
+        let max_bytes = max_bytes / shards;
+        MemoryStore {
+            shards: (0..shards)
+                .map(|_| StdMutex::new(Cache::new(max_entries, max_bytes, policy.build(max_entries))))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, key: &CacheKey) -> MutexGuard<'_, Cache> {
+        let mut hasher = DefaultHasher::new();
+        (&key.origin, &key.path, &key.query).hash(&mut hasher);
+        self.shards[hasher.finish() as usize % self.shards.len()].lock().unwrap()
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryStore {
+    async fn get(&self, key: &CacheKey, request_headers: &HeaderMap<HeaderValue>) -> Option<CachedResponse> {
+        self.shard(key).get(key, request_headers)
+    }
+
+    async fn put(&self, key: CacheKey, cached: CachedResponse) -> io::Result<()> {
+        self.shard(&key).insert(key, cached);
+        Ok(())
+    }
+
+    async fn delete(&self, key: &CacheKey) -> io::Result<()> {
+        self.shard(key).remove(key);
+        Ok(())
+    }
+
+    async fn invalidate(&self, target: &CacheKey) -> io::Result<()> {
+        self.shard(target).remove_url(target);
+        Ok(())
+    }
+
+    async fn keys(&self) -> io::Result<Vec<CacheKey>> {
+        Ok(self.shards.iter().flat_map(|shard| shard.lock().unwrap().keys()).collect())
+    }
+
+    async fn entries(&self, now: SystemTime) -> io::Result<Vec<EntryInfo>> {
+        Ok(self.shards.iter().flat_map(|shard| shard.lock().unwrap().entries(now)).collect())
+    }
+
+    async fn purge(&self, now: SystemTime) -> io::Result<()> {
+        for shard in &self.shards {
+            shard.lock().unwrap().remove_expired(now);
+        }
+        Ok(())
+    }
+
+    async fn stats(&self) -> Vec<(&'static str, CacheStats)> {
+        let mut total = CacheStats { entries: 0, bytes: 0, evictions: 0, rejections: 0 };
+        for shard in &self.shards {
+            let stats = shard.lock().unwrap().stats();
+            total.entries += stats.entries;
+            total.bytes += stats.bytes;
+            total.evictions += stats.evictions;
+            total.rejections += stats.rejections;
+        }
+        vec![("memory", total)]
+    }
+}
+
+/// Runs the blocking `DiskCache` on tokio's blocking thread pool.
+pub struct DiskStore {
+    disk: Arc<DiskCache>,
+}
+
+impl DiskStore {
+    pub fn new(disk: DiskCache) -> DiskStore {
+        DiskStore { disk: Arc::new(disk) }
+    }
+
+    async fn run<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&DiskCache) -> io::Result<T> + Send + 'static,
+    {
+        let disk = self.disk.clone();
+        task::spawn_blocking(move || f(&disk)).await.map_err(io::Error::other)?
+    }
+}
+
+#[async_trait]
+impl CacheStore for DiskStore {
+    async fn get(&self, key: &CacheKey, request_headers: &HeaderMap<HeaderValue>) -> Option<CachedResponse> {
+        let (key, request_headers) = (key.clone(), request_headers.clone());
+        self.run(move |disk| Ok(disk.get(&key, &request_headers))).await.ok()?
+    }
+
+    async fn put(&self, key: CacheKey, cached: CachedResponse) -> io::Result<()> {
+        self.run(move |disk| disk.insert(key, &cached)).await
+    }
+
+    async fn delete(&self, key: &CacheKey) -> io::Result<()> {
+        let key = key.clone();
+        self.run(move |disk| {
+            disk.remove(&key);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn invalidate(&self, target: &CacheKey) -> io::Result<()> {
+        let target = target.clone();
+        self.run(move |disk| {
+            disk.remove_url(&target);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn keys(&self) -> io::Result<Vec<CacheKey>> {
+        self.run(|disk| Ok(disk.keys())).await
+    }
+
+    async fn entries(&self, now: SystemTime) -> io::Result<Vec<EntryInfo>> {
+        self.run(move |disk| Ok(disk.entries(now))).await
+    }
+
+    /// Also writes the index out, so that it survives restarts.
+    async fn purge(&self, now: SystemTime) -> io::Result<()> {
+        self.run(move |disk| {
+            disk.remove_expired(now);
+            disk.flush()
+        })
+        .await
+    }
+
+    async fn stats(&self) -> Vec<(&'static str, CacheStats)> {
+        vec![("disk", self.disk.stats())]
+    }
+}
+
+/// A fast store in front of a larger, slower one. Writes go to both, and hits in the
+/// second tier are copied into the first.
+pub struct TieredStore {
+    first: Box<dyn CacheStore>,
+    second: Box<dyn CacheStore>,
+}
+
+impl TieredStore {
+    pub fn new(first: Box<dyn CacheStore>, second: Box<dyn CacheStore>) -> TieredStore {
+        TieredStore { first, second }
+    }
+}
+
+#[async_trait]
+impl CacheStore for TieredStore {
+    async fn get(&self, key: &CacheKey, request_headers: &HeaderMap<HeaderValue>) -> Option<CachedResponse> {
+        if let Some(cached) = self.first.get(key, request_headers).await {
+            return Some(cached);
+        }
+        let cached = self.second.get(key, request_headers).await?;
+        let _ = self.first.put(key.clone(), cached.clone()).await;
+        Some(cached)
+    }
+
+    async fn put(&self, key: CacheKey, cached: CachedResponse) -> io::Result<()> {
+        self.first.put(key.clone(), cached.clone()).await?;
+        self.second.put(key, cached).await
+    }
+
+    async fn delete(&self, key: &CacheKey) -> io::Result<()> {
+        self.first.delete(key).await?;
+        self.second.delete(key).await
+    }
+
+    async fn invalidate(&self, target: &CacheKey) -> io::Result<()> {
+        self.first.invalidate(target).await?;
+        self.second.invalidate(target).await
+    }
+
+    async fn keys(&self) -> io::Result<Vec<CacheKey>> {
+        let mut keys = self.first.keys().await?;
+        let first: HashSet<CacheKey> = keys.iter().cloned().collect();
+        keys.extend(self.second.keys().await?.into_iter().filter(|key| !first.contains(key)));
+        Ok(keys)
+    }
+
+    /// Keys stored in both tiers are listed once, with the hits of both.
+    async fn entries(&self, now: SystemTime) -> io::Result<Vec<EntryInfo>> {
+        let mut entries = self.first.entries(now).await?;
+        let positions: HashMap<CacheKey, usize> =
+            entries.iter().enumerate().map(|(position, entry)| (entry.key.clone(), position)).collect();
+        for entry in self.second.entries(now).await? {
+            match positions.get(&entry.key) {
+                Some(&position) => entries[position].hits += entry.hits,
+                None => entries.push(entry),
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn purge(&self, now: SystemTime) -> io::Result<()> {
+        self.first.purge(now).await?;
+        self.second.purge(now).await
+    }
+
+    async fn stats(&self) -> Vec<(&'static str, CacheStats)> {
+        let mut stats = self.first.stats().await;
+        stats.extend(self.second.stats().await);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::{body::Bytes, Method, Request, Response};
+
+    use super::*;
+    use crate::{
+        cache_key::{DefaultKeyBuilder, KeyBuilder},
+        cached_response::FreshnessDefaults,
+    };
+
+    fn key(method: Method, path: &str) -> CacheKey {
+        let (parts, _) = Request::builder().method(method).uri(path).body(()).unwrap().into_parts();
+        DefaultKeyBuilder::default().build(&parts, "http://example.com")
+    }
+
+    fn cached(size: usize) -> CachedResponse {
+        let (response, _) = Response::builder().header("cache-control", "max-age=60").body(()).unwrap().into_parts();
+        let now = SystemTime::now();
+        let defaults = FreshnessDefaults {
+            ttl: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+        };
+        let mut cached = CachedResponse::new(&HeaderMap::new(), &response, now, now, defaults);
+        cached.set_body(Bytes::from(vec![b'x'; size]));
+        cached
+    }
+
+    #[tokio::test]
+    async fn splits_the_limits_across_shards() {
+        // Each shard holds up to 3 entries and 250 bytes.
+        let store = MemoryStore::new(4, 10, 1000, PolicyKind::Lru);
+        for i in 0..100 {
+            store.put(key(Method::GET, &format!("/{}", i)), cached(10)).await.unwrap();
+        }
+        for shard in &store.shards {
+            assert_eq!(shard.lock().unwrap().stats().entries, 3);
+        }
+        let (_, stats) = store.stats().await[0];
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (12, 120, 88));
+
+        // A body that fits the whole store but not a shard is rejected.
+        store.put(key(Method::GET, "/large"), cached(251)).await.unwrap();
+        assert!(store.get(&key(Method::GET, "/large"), &HeaderMap::new()).await.is_none());
+        assert_eq!(store.stats().await[0].1.rejections, 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_every_method_of_a_url_in_one_shard() {
+        let store = MemoryStore::new(8, 100, 10_000, PolicyKind::Lru);
+        for method in [Method::GET, Method::HEAD, Method::POST] {
+            store.put(key(method, "/a"), cached(10)).await.unwrap();
+        }
+        store.put(key(Method::GET, "/b"), cached(10)).await.unwrap();
+        store.invalidate(&key(Method::POST, "/a")).await.unwrap();
+        assert_eq!(store.keys().await.unwrap(), vec![key(Method::GET, "/b")]);
+    }
+}
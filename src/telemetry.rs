@@ -0,0 +1,167 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use hyper::{
+    header::HeaderName,
+    http::HeaderValue,
+    service::Service,
+    HeaderMap, Uri,
+};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::TracerProvider,
+};
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracerProvider},
+    Resource,
+};
+use tracing::{Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+use crate::config::OtlpConfig;
+
+
+/// Exports spans in batches to the collector at `config.endpoint`. Spans continue the trace
+/// of an incoming `traceparent` header and pass their own on to upstreams.
+pub fn tracer_provider(config: &OtlpConfig) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = SpanExporter::builder().with_http().with_endpoint(&config.endpoint).build()?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio))))
+        .with_resource(Resource::builder().with_service_name(config.service_name.clone()).build())
+        .build())
+}
+
+pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+}
+
+/// Makes `span` a child of the trace in the request's `traceparent` and `tracestate`.
+pub fn set_parent(span: &Span, headers: &HeaderMap<HeaderValue>) {
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    let _ = span.set_parent(parent);
+}
+
+/// Replaces the trace headers of an upstream request with ones naming `span` as the parent.
+pub fn inject(span: &Span, headers: &mut HeaderMap<HeaderValue>) {
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut HeaderInjector(headers)));
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap<HeaderValue>);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap<HeaderValue>);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// A connector whose connections are each made inside a span named `name`, so that time
+/// spent connecting shows up in the upstream request's trace.
+#[derive(Clone)]
+pub struct Traced<C> {
+    inner: C,
+    name: &'static str,
+}
+
+impl<C> Traced<C> {
+    pub fn new(inner: C, name: &'static str) -> Traced<C> {
+        Traced { inner, name }
+    }
+}
+
+impl<C> Service<Uri> for Traced<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+{
+    type Response = C::Response;
+    type Error = C::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<C::Response, C::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), C::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let span = tracing::info_span!("connect", otel.name = self.name, host = uri.host().unwrap_or_default());
+        Box::pin(self.inner.call(uri).instrument(span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap<HeaderValue> {
+        pairs.iter().map(|(name, value)| (HeaderName::from_static(name), HeaderValue::from_static(value))).collect()
+    }
+
+    // The trace ID, parent span ID and flags of a `traceparent`.
+    fn traceparent(headers: &HeaderMap<HeaderValue>) -> (String, String, String) {
+        let parts: Vec<&str> = headers["traceparent"].to_str().unwrap().split('-').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "00");
+        (parts[1].to_owned(), parts[2].to_owned(), parts[3].to_owned())
+    }
+
+    #[test]
+    fn continues_incoming_traces_towards_upstreams() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = SdkTracerProvider::builder().build();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer(&provider)));
+
+        let incoming = headers(&[
+            ("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            ("tracestate", "vendor=1"),
+        ]);
+        let span = tracing::info_span!("request");
+        set_parent(&span, &incoming);
+        let upstream = tracing::info_span!(parent: &span, "upstream");
+        // Whatever the client sent is replaced by the proxy's own span.
+        let mut outgoing = incoming.clone();
+        inject(&upstream, &mut outgoing);
+        let (trace_id, parent_id, flags) = traceparent(&outgoing);
+        assert_eq!((trace_id.as_str(), flags.as_str()), (TRACE_ID, "01"));
+        assert_ne!(parent_id, "00f067aa0ba902b7");
+        assert_eq!(outgoing["tracestate"], "vendor=1");
+
+        // Without a traceparent the request starts a trace of its own.
+        let span = tracing::info_span!("request");
+        set_parent(&span, &HeaderMap::new());
+        let mut outgoing = HeaderMap::new();
+        inject(&span, &mut outgoing);
+        let (trace_id, _, _) = traceparent(&outgoing);
+        assert_ne!(trace_id, TRACE_ID);
+        assert_ne!(trace_id, "0".repeat(32));
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+};
+
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+
+use crate::config::TlsConfig;
+
+
+/// Trusts the platform's root certificates. Upstreams are offered HTTP/2 and HTTP/1.1 by
+/// the connector, which adds the ALPN protocols itself.
+pub fn client_config() -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    for certificate in rustls_native_certs::load_native_certs().unwrap_or_default() {
+        // Certificates rustls can't parse are left out rather than failing every connection.
+        let _ = roots.add(&Certificate(certificate.0));
+    }
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+/// Serves the certificate chain and private key from the PEM files in `config`. The ALPN
+/// protocols are left to the acceptor.
+pub fn server_config(config: &TlsConfig) -> io::Result<ServerConfig> {
+    let certificates = rustls_pemfile::certs(&mut open(&config.cert)?)?;
+    if certificates.is_empty() {
+        return Err(invalid_data(format!("no certificate in {}", config.cert.display())));
+    }
+    let key = rustls_pemfile::read_all(&mut open(&config.key)?)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data(format!("no private key in {}", config.key.display())))?;
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates.into_iter().map(Certificate).collect(), PrivateKey(key))
+        .map_err(|error| invalid_data(error.to_string()))
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}